@@ -1,16 +1,24 @@
 #!/bin/bash
 
-if [ "$#" -ne 1 ]; then
+usage() {
     echo "Usage: $0 <path_to_image>"
+    echo "       $0 -m <message>"
     exit 1
-fi
+}
 
-IMAGE_PATH="$1"
 PRIVATE_KEY_PATH="./certs/staging.pem"
 
-if [ ! -f "$IMAGE_PATH" ]; then
-    echo >&2 "Error: Image file not found"
-    exit 1
+if [ "$1" = "-m" ]; then
+    [ "$#" -ne 2 ] && usage
+    MESSAGE="$2"
+else
+    [ "$#" -ne 1 ] && usage
+    IMAGE_PATH="$1"
+
+    if [ ! -f "$IMAGE_PATH" ]; then
+        echo >&2 "Error: Image file not found"
+        exit 1
+    fi
 fi
 
 if [ ! -f "$PRIVATE_KEY_PATH" ]; then
@@ -18,7 +26,11 @@ if [ ! -f "$PRIVATE_KEY_PATH" ]; then
     exit 1
 fi
 
-SIGNATURE=$(openssl dgst -sha1 -sign "$PRIVATE_KEY_PATH" "$IMAGE_PATH" | base64 -w0)
+if [ -n "$IMAGE_PATH" ]; then
+    SIGNATURE=$(openssl dgst -sha1 -sign "$PRIVATE_KEY_PATH" "$IMAGE_PATH" | base64 -w0)
+else
+    SIGNATURE=$(printf '%s' "$MESSAGE" | openssl dgst -sha1 -sign "$PRIVATE_KEY_PATH" | base64 -w0)
+fi
 
 if [ -z "$SIGNATURE" ]; then
     echo >&2 "Error: Failed to create signature"
@@ -0,0 +1,171 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    web,
+};
+use log::Record;
+use log4rs::{
+    config::{Deserialize, Deserializers},
+    encode::{pattern::PatternEncoder, Encode, Write},
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::rest::resource_from_request;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id taken over from a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    /// Id of the request the current task is handling.
+    static REQUEST_ID: String;
+}
+
+/// Id of the request that is currently being handled, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs `future` with `request_id` attached to every log line it emits.
+pub async fn scope<F: std::future::Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// Runs `f` with `request_id` attached to every log line it emits, for work moved
+/// off the task, e.g. onto a blocking thread, which doesn't inherit its request id.
+pub fn sync_scope<F: FnOnce() -> R, R>(request_id: Option<String>, f: F) -> R {
+    match request_id {
+        Some(request_id) => REQUEST_ID.sync_scope(request_id, f),
+        None => f(),
+    }
+}
+
+/// Takes over the request id sent by the client or a proxy, or generates a new one.
+///
+/// Ids that are too long or contain anything but alphanumerics, `-`, `_` and `.` are
+/// replaced, so they can't tamper with the logs.
+pub fn request_id(request: &ServiceRequest) -> String {
+    request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Writes the access log line of a handled request, and returns its id to the client.
+pub fn finish<B: MessageBody>(
+    response: &mut ServiceResponse<B>,
+    request_id: &str,
+    elapsed: Duration,
+) {
+    let request = response.request();
+
+    let size = web::Query::<HashMap<String, String>>::from_query(request.query_string())
+        .ok()
+        .and_then(|query| query.get("size").cloned());
+
+    let bytes = match response.response().body().size() {
+        BodySize::Sized(bytes) => Some(bytes),
+        _ => None,
+    };
+
+    let cache_status = response
+        .headers()
+        .get("X-Origin-Status")
+        .and_then(|status| status.to_str().ok());
+
+    let line = json!({
+        "request_id": request_id,
+        "method": request.method().as_str(),
+        "path": request.path(),
+        "resource": resource_from_request(request).map(|resource| resource.to_string()),
+        "id": request.match_info().get("id"),
+        "size": size,
+        "status": response.status().as_u16(),
+        "cache_status": cache_status,
+        "bytes": bytes,
+        "latency_ms": elapsed.as_secs_f64() * 1000.0,
+    });
+
+    log::info!(target: "access", "{line}");
+
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// A pattern encoder that prefixes the message with the id of the request that is
+/// being handled, e.g. `[3f2a...] Failed to write ... to cache`.
+#[derive(Debug)]
+pub struct RequestIdEncoder(PatternEncoder);
+
+impl RequestIdEncoder {
+    /// Encodes records with a [`PatternEncoder`] of `pattern`, after the request id.
+    pub fn new(pattern: &str) -> Self {
+        Self(PatternEncoder::new(pattern))
+    }
+}
+
+impl Encode for RequestIdEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        match current_request_id() {
+            Some(request_id) => self.0.encode(
+                w,
+                &Record::builder()
+                    .args(format_args!("[{request_id}] {}", record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.0.encode(w, record),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestIdEncoderConfig {
+    pattern: Option<String>,
+}
+
+pub struct RequestIdEncoderDeserializer;
+
+impl Deserialize for RequestIdEncoderDeserializer {
+    type Trait = dyn Encode;
+    type Config = RequestIdEncoderConfig;
+
+    fn deserialize(
+        &self,
+        config: RequestIdEncoderConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Encode>> {
+        let encoder = match config.pattern {
+            Some(pattern) => PatternEncoder::new(&pattern),
+            None => PatternEncoder::default(),
+        };
+
+        Ok(Box::new(RequestIdEncoder(encoder)))
+    }
+}
+
+/// The log4rs deserializers, including the `request_id` encoder.
+pub fn deserializers() -> Deserializers {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert("request_id", RequestIdEncoderDeserializer);
+    deserializers
+}
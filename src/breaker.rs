@@ -0,0 +1,93 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BreakerState {
+    /// Calls go through
+    Closed,
+    /// Calls are rejected until the cooldown has passed
+    Open,
+    /// A single trial call is let through to see if the dependency recovered.
+    /// Another one is let through if it doesn't report back within the cooldown.
+    HalfOpen,
+}
+
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    /// When the last trial call was let through
+    trial_at: Option<Instant>,
+}
+
+/// Stops calls to a failing dependency after `threshold` consecutive failures,
+/// and lets a trial call through once `cooldown` has passed.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                opened_at: None,
+                trial_at: None,
+            }),
+        }
+    }
+
+    /// Returns whether a call may be made right now.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        let since = match inner.state {
+            BreakerState::Closed => return true,
+            // The trial call may have been dropped without reporting back
+            BreakerState::HalfOpen => inner.trial_at,
+            BreakerState::Open => inner.opened_at,
+        };
+        let cooled_down = since.is_some_and(|since| since.elapsed() >= self.cooldown);
+
+        if cooled_down {
+            inner.state = BreakerState::HalfOpen;
+            inner.trial_at = Some(Instant::now());
+        }
+
+        cooled_down
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+        inner.opened_at = None;
+        inner.trial_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.failures += 1;
+
+        if inner.state == BreakerState::HalfOpen || inner.failures >= self.threshold {
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use lru::LruCache;
+
+use super::{CacheBackend, Health};
+
+struct Entry {
+    value: Bytes,
+    expires_at: Instant,
+}
+
+struct Inner {
+    entries: LruCache<String, Entry>,
+    /// Sum of the sizes of all cached values
+    size: usize,
+    indexes: HashMap<String, HashSet<String>>,
+    /// The index every indexed key is recorded in
+    indexed_in: HashMap<String, String>,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.pop(key) {
+            self.size -= entry.value.len();
+        }
+
+        self.unindex(key);
+    }
+
+    fn evict_lru(&mut self) -> bool {
+        let Some((key, evicted)) = self.entries.pop_lru() else {
+            return false;
+        };
+
+        self.size -= evicted.value.len();
+        self.unindex(&key);
+
+        true
+    }
+
+    /// Removes `key` from its index, and the index once it's empty.
+    fn unindex(&mut self, key: &str) {
+        let Some(index) = self.indexed_in.remove(key) else {
+            return;
+        };
+
+        if let Some(keys) = self.indexes.get_mut(&index) {
+            keys.remove(key);
+
+            if keys.is_empty() {
+                self.indexes.remove(&index);
+            }
+        }
+    }
+}
+
+/// Caches rendered images in the memory of the process.
+///
+/// The cache is bounded by the total size of the cached values in bytes. When
+/// a new value doesn't fit, the least recently used values are evicted.
+pub struct MemoryCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    started_at: Instant,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                size: 0,
+                indexes: HashMap::new(),
+                indexed_in: HashMap::new(),
+            }),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Number of keys recorded in indexes, which never exceeds the number of cached values.
+    pub fn indexed_keys(&self) -> usize {
+        self.inner.lock().unwrap().indexed_in.len()
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self.get_with_ttl(key).await?.map(|(value, _)| value))
+    }
+
+    async fn get_with_ttl(&self, key: &str) -> Result<Option<(Bytes, Option<Duration>)>> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let expired = match inner.entries.get(key) {
+            Some(entry) if entry.expires_at > now => {
+                return Ok(Some((entry.value.clone(), Some(entry.expires_at - now))));
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            inner.remove(key);
+        }
+
+        Ok(None)
+    }
+
+    async fn put(&self, key: &str, value: Bytes, ttl: Duration) -> Result<()> {
+        if value.len() > self.capacity {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let size = value.len();
+        let entry = Entry {
+            value,
+            expires_at: Instant::now() + ttl,
+        };
+
+        if let Some(previous) = inner.entries.put(key.to_string(), entry) {
+            inner.size -= previous.value.len();
+        }
+
+        inner.size += size;
+
+        while inner.size > self.capacity && inner.evict_lru() {}
+
+        Ok(())
+    }
+
+    /// Only cached keys are indexed, and they are removed from their index again once
+    /// they are evicted or expire, so indexes don't outgrow the cache. Indexes thus
+    /// don't need to expire on their own.
+    async fn add_to_index(&self, index: &str, key: &str, _ttl: Duration) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !inner.entries.contains(key) {
+            return Ok(());
+        }
+
+        inner.unindex(key);
+        inner
+            .indexes
+            .entry(index.to_string())
+            .or_default()
+            .insert(key.to_string());
+        inner.indexed_in.insert(key.to_string(), index.to_string());
+
+        Ok(())
+    }
+
+    async fn purge_index(&self, index: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        for key in inner.indexes.remove(index).unwrap_or_default() {
+            inner.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    /// The cursor is an offset into the sorted keys, so pages may overlap or skip
+    /// keys that are added or evicted in between.
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<(u64, Vec<String>)> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut keys: Vec<&String> = inner
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+
+        let start = cursor as usize;
+        let end = start.saturating_add(count).min(keys.len());
+        let next = if end < keys.len() { end as u64 } else { 0 };
+
+        Ok((
+            next,
+            keys.get(start..end)
+                .unwrap_or_default()
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+        ))
+    }
+
+    async fn health(&self) -> Result<Health> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(Health {
+            memory_usage: format!("{:.2}M", inner.size as f64 / (1024.0 * 1024.0)),
+            num_keys: inner.entries.len() as u64,
+            uptime_seconds: self.started_at.elapsed().as_secs(),
+        })
+    }
+}
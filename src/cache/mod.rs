@@ -0,0 +1,452 @@
+pub mod memory;
+pub mod policy;
+pub mod redis;
+
+use std::{env, fmt::Display, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    breaker::BreakerState,
+    config::{CacheBackendKind, CdnConfig},
+    metrics::METRICS,
+    redis_pool::RedisPool,
+    rest::Resource,
+};
+
+use self::{
+    memory::MemoryCache,
+    policy::{CachePolicy, CacheStats},
+    redis::RedisCache,
+};
+
+/// Prefix of every key, so the cache can be shared with other services.
+const KEY_NAMESPACE: &str = "rs-cdn";
+
+/// Version of the key schema. Changing it makes every existing entry unreachable,
+/// so it has to be bumped whenever the layout of keys or values changes.
+const KEY_SCHEMA_VERSION: u32 = 2;
+
+/// How long another instance may take to render an image before we render it ourselves.
+pub const LOCK_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub memory_usage: String,
+    pub num_keys: u64,
+    pub uptime_seconds: u64,
+}
+
+/// A store for rendered images.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    /// Like [`CacheBackend::get`], along with how long the value has left to live, if
+    /// the backend knows.
+    async fn get_with_ttl(&self, key: &str) -> Result<Option<(Bytes, Option<Duration>)>> {
+        Ok(self.get(key).await?.map(|value| (value, None)))
+    }
+
+    async fn put(&self, key: &str, value: Bytes, ttl: Duration) -> Result<()>;
+    async fn health(&self) -> Result<Health>;
+
+    /// Records `key` in the set of keys named `index`, which expires after `ttl`.
+    async fn add_to_index(&self, index: &str, key: &str, ttl: Duration) -> Result<()>;
+
+    /// Removes every key recorded in `index`, and the index itself.
+    async fn purge_index(&self, index: &str) -> Result<()>;
+
+    /// Checks that the backend can be reached.
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns up to about `count` keys starting with `prefix`, and the cursor to
+    /// continue from, which is 0 once all keys were returned.
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<(u64, Vec<String>)>;
+
+    /// Tries to take a short-lived lock on `key`, shared by every instance using the
+    /// backend. Returns a token to release it with, or `None` if it is already taken.
+    ///
+    /// Backends local to the process don't need one, concurrent requests in the same
+    /// process are already coalesced.
+    async fn try_lock(&self, _key: &str, _ttl: Duration) -> Result<Option<String>> {
+        Ok(Some(String::new()))
+    }
+
+    async fn unlock(&self, _key: &str, _token: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the lock on `key` is still held, by this or any other instance.
+    async fn is_locked(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Starts establishing connections the backend needs, without waiting for them.
+    fn connect(&self) {}
+
+    /// State of the circuit breaker in front of the backend, if it has one.
+    fn breaker_state(&self) -> Option<BreakerState> {
+        None
+    }
+}
+
+/// The key of a rendered image.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    resource: String,
+    id: String,
+    hash: String,
+    ext: String,
+    size: u32,
+}
+
+impl CacheKey {
+    pub fn new(resource: &Resource, id: &str, hash: &str, ext: &str, size: u32) -> Self {
+        Self {
+            resource: resource.to_string(),
+            id: id.to_string(),
+            hash: hash.to_string(),
+            ext: ext.to_string(),
+            size,
+        }
+    }
+
+    /// The key of the set of all keys cached for the same resource and id.
+    fn index(&self) -> String {
+        index_key(&self.resource, &self.id)
+    }
+
+    /// The key of the generation of the resource and id, see [`Cache`].
+    fn generation(&self) -> String {
+        generation_key(&self.resource, &self.id)
+    }
+}
+
+impl Display for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{KEY_NAMESPACE}:v{KEY_SCHEMA_VERSION}:{}:{}:{}:{}:{}",
+            self.resource, self.id, self.hash, self.ext, self.size
+        )
+    }
+}
+
+fn record_lookup(tier: CacheTier, hit: bool) {
+    METRICS
+        .cache_lookups
+        .with_label_values(&[&tier.to_string(), if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+fn index_key(resource: &str, id: &str) -> String {
+    format!("{KEY_NAMESPACE}:v{KEY_SCHEMA_VERSION}:{resource}:{id}:keys")
+}
+
+fn generation_key(resource: &str, id: &str) -> String {
+    format!("{KEY_NAMESPACE}:v{KEY_SCHEMA_VERSION}:{resource}:{id}:generation")
+}
+
+/// The tier of the cache a value was found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheTier {
+    /// The in-process cache in front of the backend
+    Memory,
+    /// The configured backend
+    Cache,
+}
+
+impl Display for CacheTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CacheTier::Memory => "memory",
+            CacheTier::Cache => "cache",
+        })
+    }
+}
+
+/// A two-tier cache: an optional, size-bounded in-process cache (L1) in front
+/// of the configured backend (L2). Values found in L2 are promoted to L1, for no
+/// longer than they have left to live in L2.
+///
+/// Every instance has an L1 of its own, so values in L1 are keyed by the generation
+/// of their id, a random token kept in L2 that changes whenever the id is purged.
+/// Once it changes, the values cached by any instance beforehand can't be found
+/// anymore, and age out of L1.
+///
+/// What is cached, and for how long, is decided by the [`CachePolicy`]. Values are
+/// only compressed in L2, L1 keeps them ready to be served.
+#[derive(Clone)]
+pub struct Cache {
+    memory: Option<Arc<MemoryCache>>,
+    backend: Arc<dyn CacheBackend>,
+    policy: Arc<CachePolicy>,
+}
+
+impl Cache {
+    pub fn new(
+        memory: Option<MemoryCache>,
+        backend: Arc<dyn CacheBackend>,
+        policy: CachePolicy,
+    ) -> Self {
+        Cache {
+            memory: memory.map(Arc::new),
+            backend,
+            policy: Arc::new(policy),
+        }
+    }
+
+    pub fn from_config(config: &CdnConfig) -> Result<Self> {
+        let backend: Arc<dyn CacheBackend> = match config.cache.backend {
+            CacheBackendKind::Redis => {
+                let redis_host = env::var("REDIS_HOST").unwrap_or("redis://127.0.0.1".to_string());
+
+                Arc::new(RedisCache::new(RedisPool::new(&redis_host, &config.redis)?))
+            }
+            CacheBackendKind::Memory => Arc::new(MemoryCache::new(config.cache.memory_capacity)),
+        };
+
+        let memory = match config.cache.l1_capacity {
+            0 => None,
+            capacity => Some(MemoryCache::new(capacity)),
+        };
+
+        Ok(Self::new(memory, backend, CachePolicy::new(&config.cache)))
+    }
+
+    pub fn connect(&self) {
+        self.backend.connect();
+    }
+
+    /// Whether images for `key` are cached at all.
+    pub fn is_cacheable(&self, key: &CacheKey) -> bool {
+        self.policy.rule(key).is_some()
+    }
+
+    /// Returns the cached value of `key` and the tier it was found in, treating any
+    /// failure of the backend as a miss.
+    #[tracing::instrument(name = "cache_lookup", skip(self), fields(%key))]
+    pub async fn get(&self, key: &CacheKey) -> Option<(Bytes, CacheTier)> {
+        let Some(rule) = self.policy.rule(key) else {
+            self.policy.record_skipped();
+            return None;
+        };
+
+        let found = self.lookup(key).await;
+
+        match found {
+            Some(_) => rule.record_hit(),
+            None => rule.record_miss(),
+        }
+
+        found
+    }
+
+    /// Like [`Cache::get`], but without counting towards the statistics, for
+    /// repeatedly checking whether a value showed up.
+    pub async fn poll(&self, key: &CacheKey) -> Option<(Bytes, CacheTier)> {
+        self.policy.rule(key)?;
+        self.lookup(key).await
+    }
+
+    /// The in-process cache along with the key of `key` in it, if it is enabled and
+    /// the generation of the id can be told.
+    async fn memory_key(&self, key: &CacheKey) -> Option<(&MemoryCache, String)> {
+        let memory = self.memory.as_deref()?;
+
+        // Without knowing the generation, values in L1 may have been purged elsewhere
+        let generation = match self.backend.get(&key.generation()).await {
+            Ok(generation) => generation.unwrap_or_default(),
+            Err(why) => {
+                log::debug!("Reading the generation of {key} failed: {why}");
+                return None;
+            }
+        };
+
+        Some((
+            memory,
+            format!("{key}@{}", String::from_utf8_lossy(&generation)),
+        ))
+    }
+
+    async fn lookup(&self, cache_key: &CacheKey) -> Option<(Bytes, CacheTier)> {
+        let key = &cache_key.to_string();
+        let memory = self.memory_key(cache_key).await;
+
+        if let Some((memory, memory_key)) = &memory {
+            if let Ok(Some(data)) = memory.get(memory_key).await {
+                record_lookup(CacheTier::Memory, true);
+                return Some((data, CacheTier::Memory));
+            }
+
+            record_lookup(CacheTier::Memory, false);
+        }
+
+        let (data, remaining_ttl) = match self.backend.get_with_ttl(key).await {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                record_lookup(CacheTier::Cache, false);
+                return None;
+            }
+            Err(why) => {
+                log::debug!("Cache lookup of {key} failed: {why}");
+                record_lookup(CacheTier::Cache, false);
+                return None;
+            }
+        };
+
+        record_lookup(CacheTier::Cache, true);
+
+        let data = match self.policy.decode(data) {
+            Ok(data) => data,
+            Err(why) => {
+                log::debug!("Cached value of {key} is unreadable: {why}");
+                return None;
+            }
+        };
+
+        if let (Some((memory, memory_key)), Some(rule)) = (memory, self.policy.rule(cache_key)) {
+            let ttl = remaining_ttl.map_or(rule.ttl(), |remaining| remaining.min(rule.ttl()));
+
+            let _ = memory.put(&memory_key, data.clone(), ttl).await;
+            let _ = memory
+                .add_to_index(&cache_key.index(), &memory_key, self.policy.max_ttl())
+                .await;
+        }
+
+        Some((data, CacheTier::Cache))
+    }
+
+    /// Caches `value` according to the policy for `key`. Values of sizes that are
+    /// skipped, or that are too large, are silently dropped.
+    #[tracing::instrument(name = "cache_store", skip(self, value), fields(%key, bytes = value.len()))]
+    pub async fn put(&self, key: &CacheKey, value: Bytes) -> Result<()> {
+        let Some(rule) = self.policy.rule(key) else {
+            return Ok(());
+        };
+
+        if !self.policy.fits(&value) {
+            rule.record_too_large();
+            return Ok(());
+        }
+
+        let index = key.index();
+        let index_ttl = self.policy.max_ttl();
+        let memory = self.memory_key(key).await;
+        let key = &key.to_string();
+        let encoded = self.policy.encode(&value)?;
+
+        if let Some((memory, memory_key)) = memory {
+            memory.put(&memory_key, value, rule.ttl()).await?;
+            memory.add_to_index(&index, &memory_key, index_ttl).await?;
+        }
+
+        self.backend.put(key, encoded, rule.ttl()).await?;
+        self.backend.add_to_index(&index, key, index_ttl).await?;
+
+        rule.record_stored();
+
+        Ok(())
+    }
+
+    /// Removes every rendition of `id` from the cache, e.g. after it was replaced.
+    ///
+    /// The in-process caches of other instances are purged by changing the generation
+    /// of the id, after L2 was purged so that they can't promote what's left of it.
+    pub async fn purge(&self, resource: &Resource, id: &str) -> Result<()> {
+        let index = index_key(&resource.to_string(), id);
+
+        if let Some(memory) = &self.memory {
+            memory.purge_index(&index).await?;
+        }
+
+        self.backend.purge_index(&index).await?;
+
+        // Values in L1 never outlive the longest TTL, and neither has to the generation
+        let generation = Uuid::new_v4().to_string();
+        self.backend
+            .put(
+                &generation_key(&resource.to_string(), id),
+                Bytes::from(generation),
+                self.policy.max_ttl(),
+            )
+            .await
+    }
+
+    /// Tries to take the lock for rendering `key`, see [`CacheBackend::try_lock`].
+    ///
+    /// If the backend can't be reached, the lock is considered taken by us, so the
+    /// image is still rendered.
+    pub async fn try_lock(&self, key: &CacheKey) -> Option<String> {
+        match self
+            .backend
+            .try_lock(&format!("lock:{key}"), LOCK_TTL)
+            .await
+        {
+            Ok(token) => token,
+            Err(why) => {
+                log::debug!("Locking {key} failed: {why}");
+                Some(String::new())
+            }
+        }
+    }
+
+    pub async fn unlock(&self, key: &CacheKey, token: &str) {
+        if let Err(why) = self.backend.unlock(&format!("lock:{key}"), token).await {
+            log::debug!("Unlocking {key} failed: {why}");
+        }
+    }
+
+    /// Whether another instance still holds the lock for rendering `key`. If the
+    /// backend can't be reached, the lock is considered released.
+    pub async fn is_locked(&self, key: &CacheKey) -> bool {
+        match self.backend.is_locked(&format!("lock:{key}")).await {
+            Ok(locked) => locked,
+            Err(why) => {
+                log::debug!("Checking the lock of {key} failed: {why}");
+                false
+            }
+        }
+    }
+
+    /// Health of the backend.
+    pub async fn health(&self) -> Result<Health> {
+        self.backend.health().await
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.backend.ping().await
+    }
+
+    /// Returns a page of the keys of rendered images in the backend, see
+    /// [`CacheBackend::scan`].
+    pub async fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<String>)> {
+        self.backend
+            .scan(&format!("{KEY_NAMESPACE}:"), cursor, count)
+            .await
+    }
+
+    /// Health of the in-process cache, if enabled.
+    pub async fn memory_health(&self) -> Option<Health> {
+        match &self.memory {
+            Some(memory) => memory.health().await.ok(),
+            None => None,
+        }
+    }
+
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.backend.breaker_state()
+    }
+
+    /// Hit and miss counts of every rule of the cache policy.
+    pub fn stats(&self) -> CacheStats {
+        self.policy.stats()
+    }
+}
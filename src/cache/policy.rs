@@ -0,0 +1,216 @@
+use std::{
+    io::{Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::Serialize;
+
+use crate::config::{CacheCompression, CacheConfig};
+
+use super::CacheKey;
+
+/// Marks how a value in the cache backend is encoded.
+const TAG_RAW: u8 = 0;
+const TAG_DEFLATE: u8 = 1;
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stored: AtomicU64,
+    too_large: AtomicU64,
+}
+
+/// How long images matching a resource and size are cached.
+pub struct Rule {
+    name: String,
+    resource: Option<String>,
+    size: Option<u32>,
+    ttl: Duration,
+    counters: Counters,
+}
+
+impl Rule {
+    fn new(name: String, resource: Option<String>, size: Option<u32>, ttl: u64) -> Self {
+        Self {
+            name,
+            resource,
+            size,
+            ttl: Duration::from_secs(ttl),
+            counters: Counters::default(),
+        }
+    }
+
+    fn matches(&self, key: &CacheKey) -> bool {
+        self.resource
+            .as_ref()
+            .is_none_or(|resource| *resource == key.resource)
+            && self.size.is_none_or(|size| size == key.size)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn record_hit(&self) {
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stored(&self) {
+        self.counters.stored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_too_large(&self) {
+        self.counters.too_large.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> RuleStats {
+        let hits = self.counters.hits.load(Ordering::Relaxed);
+        let misses = self.counters.misses.load(Ordering::Relaxed);
+
+        RuleStats {
+            name: self.name.clone(),
+            ttl: self.ttl.as_secs(),
+            hits,
+            misses,
+            hit_ratio: match hits + misses {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            },
+            stored: self.counters.stored.load(Ordering::Relaxed),
+            too_large: self.counters.too_large.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleStats {
+    pub name: String,
+    pub ttl: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    pub stored: u64,
+    /// Images that weren't cached because they exceeded the maximum object size
+    pub too_large: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub rules: Vec<RuleStats>,
+    /// Requests for sizes that are never cached
+    pub skipped: u64,
+}
+
+/// Decides whether and for how long rendered images are cached, and how they are
+/// encoded in the cache backend.
+pub struct CachePolicy {
+    /// The configured rules, followed by the default rule, which matches everything
+    rules: Vec<Rule>,
+    skip_sizes: Vec<u32>,
+    max_object_size: usize,
+    compression: CacheCompression,
+    skipped: AtomicU64,
+}
+
+impl CachePolicy {
+    pub fn new(config: &CacheConfig) -> Self {
+        let mut rules: Vec<Rule> = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let name = format!(
+                    "{}:{}",
+                    rule.resource.as_deref().unwrap_or("*"),
+                    rule.size.map_or("*".to_string(), |size| size.to_string())
+                );
+
+                Rule::new(name, rule.resource.clone(), rule.size, rule.ttl)
+            })
+            .collect();
+
+        rules.push(Rule::new("default".to_string(), None, None, config.ttl));
+
+        Self {
+            rules,
+            skip_sizes: config.skip_sizes.clone(),
+            max_object_size: config.max_object_size,
+            compression: config.compression,
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// The rule for `key`, or `None` if images of its size are never cached.
+    pub fn rule(&self, key: &CacheKey) -> Option<&Rule> {
+        if self.skip_sizes.contains(&key.size) {
+            return None;
+        }
+
+        self.rules.iter().find(|rule| rule.matches(key))
+    }
+
+    /// Counts a request that bypassed the cache because of its size.
+    pub fn record_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fits(&self, value: &[u8]) -> bool {
+        value.len() <= self.max_object_size
+    }
+
+    /// The longest TTL of all rules, which keys indexed per id have to outlive.
+    pub fn max_ttl(&self) -> Duration {
+        self.rules
+            .iter()
+            .map(|rule| rule.ttl)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Encodes a value for the cache backend, prefixed with how it is encoded so it
+    /// can still be read after the compression setting changed.
+    pub fn encode(&self, value: &[u8]) -> Result<Bytes> {
+        match self.compression {
+            CacheCompression::None => {
+                let mut encoded = Vec::with_capacity(value.len() + 1);
+                encoded.push(TAG_RAW);
+                encoded.extend_from_slice(value);
+                Ok(Bytes::from(encoded))
+            }
+            CacheCompression::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![TAG_DEFLATE], Compression::default());
+                encoder.write_all(value)?;
+                Ok(Bytes::from(encoder.finish()?))
+            }
+        }
+    }
+
+    /// Decodes a value read from the cache backend, see [`CachePolicy::encode`].
+    pub fn decode(&self, value: Bytes) -> Result<Bytes> {
+        match value.first() {
+            Some(&TAG_RAW) => Ok(value.slice(1..)),
+            Some(&TAG_DEFLATE) => {
+                let mut decoded = Vec::new();
+                DeflateDecoder::new(&value[1..]).read_to_end(&mut decoded)?;
+                Ok(Bytes::from(decoded))
+            }
+            Some(tag) => bail!("Unknown encoding {tag} of cached value"),
+            None => bail!("Empty cached value"),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            rules: self.rules.iter().map(Rule::stats).collect(),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use redis::{AsyncCommands, Script};
+
+use crate::{breaker::BreakerState, redis_pool::RedisPool};
+
+use super::{CacheBackend, Health};
+
+macro_rules! get_info_key {
+    ($info:expr, $key:expr) => {
+        $info
+            .lines()
+            .find(|line| line.starts_with($key))
+            .map(|line| line.trim_start_matches($key).to_string())
+    };
+}
+
+/// Deletes a lock only if it is still held with the given token.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+else
+    return 0
+end
+"#;
+
+/// Caches rendered images in Redis, so they are shared between instances.
+pub struct RedisCache {
+    redis: RedisPool,
+    /// Identifies the locks taken by this instance
+    instance_id: String,
+    lock_counter: AtomicUsize,
+}
+
+impl RedisCache {
+    pub fn new(redis: RedisPool) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        Self {
+            redis,
+            instance_id: format!("{}-{started_at:x}", process::id()),
+            lock_counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let data = self
+            .redis
+            .query(|mut con| async move { con.get::<_, Vec<u8>>(key).await })
+            .await?;
+
+        Ok(Some(Bytes::from(data)).filter(|data| !data.is_empty()))
+    }
+
+    async fn get_with_ttl(&self, key: &str) -> Result<Option<(Bytes, Option<Duration>)>> {
+        let (data, ttl): (Vec<u8>, i64) =
+            self.redis
+                .query(|mut con| async move {
+                    redis::pipe().get(key).pttl(key).query_async(&mut con).await
+                })
+                .await?;
+
+        // PTTL is negative for keys that don't exist or don't expire
+        let ttl = u64::try_from(ttl).ok().map(Duration::from_millis);
+
+        Ok(Some(Bytes::from(data))
+            .filter(|data| !data.is_empty())
+            .map(|data| (data, ttl)))
+    }
+
+    async fn put(&self, key: &str, value: Bytes, ttl: Duration) -> Result<()> {
+        self.redis
+            .query(|mut con| async move {
+                con.set_ex::<_, _, ()>(key, value.as_ref(), ttl.as_secs() as usize)
+                    .await
+            })
+            .await
+    }
+
+    async fn add_to_index(&self, index: &str, key: &str, ttl: Duration) -> Result<()> {
+        self.redis
+            .query(|mut con| async move {
+                redis::pipe()
+                    .sadd(index, key)
+                    .ignore()
+                    .expire(index, ttl.as_secs() as usize)
+                    .ignore()
+                    .query_async::<_, ()>(&mut con)
+                    .await
+            })
+            .await
+    }
+
+    async fn purge_index(&self, index: &str) -> Result<()> {
+        let keys: Vec<String> = self
+            .redis
+            .query(|mut con| async move { con.smembers(index).await })
+            .await?;
+
+        self.redis
+            .query(|mut con| async move {
+                let mut pipe = redis::pipe();
+
+                // DEL without any keys is an error
+                if !keys.is_empty() {
+                    pipe.del(keys).ignore();
+                }
+
+                pipe.del(index)
+                    .ignore()
+                    .query_async::<_, ()>(&mut con)
+                    .await
+            })
+            .await
+    }
+
+    async fn try_lock(&self, key: &str, ttl: Duration) -> Result<Option<String>> {
+        let count = self.lock_counter.fetch_add(1, Ordering::Relaxed);
+        let token = format!("{}:{count}", self.instance_id);
+        let value = token.as_str();
+
+        let acquired: Option<String> = self
+            .redis
+            .query(|mut con| async move {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query_async(&mut con)
+                    .await
+            })
+            .await?;
+
+        Ok(acquired.map(|_| token))
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+        self.redis
+            .query(|mut con| async move {
+                Script::new(UNLOCK_SCRIPT)
+                    .key(key)
+                    .arg(token)
+                    .invoke_async::<_, ()>(&mut con)
+                    .await
+            })
+            .await
+    }
+
+    async fn is_locked(&self, key: &str) -> Result<bool> {
+        self.redis
+            .query(|mut con| async move { con.exists(key).await })
+            .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.redis
+            .query(|mut con| async move {
+                redis::cmd("PING").query_async::<_, String>(&mut con).await
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<(u64, Vec<String>)> {
+        self.redis
+            .query(|mut con| async move {
+                redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(format!("{prefix}*"))
+                    .arg("COUNT")
+                    .arg(count)
+                    .query_async(&mut con)
+                    .await
+            })
+            .await
+    }
+
+    async fn health(&self) -> Result<Health> {
+        let (info, num_keys): (String, u64) = self
+            .redis
+            .query(|mut con| async move {
+                redis::pipe()
+                    .cmd("INFO")
+                    .cmd("DBSIZE")
+                    .query_async(&mut con)
+                    .await
+            })
+            .await?;
+
+        let memory_usage =
+            get_info_key!(&info, "used_memory_human:").unwrap_or_else(|| "(error)".to_string());
+
+        let uptime_seconds = get_info_key!(&info, "uptime_in_seconds:")
+            .map(|uptime| uptime.parse().unwrap_or(0))
+            .unwrap_or(0);
+
+        Ok(Health {
+            memory_usage,
+            num_keys,
+            uptime_seconds,
+        })
+    }
+
+    fn connect(&self) {
+        self.redis.connect_in_background();
+    }
+
+    fn breaker_state(&self) -> Option<BreakerState> {
+        Some(self.redis.breaker_state())
+    }
+}
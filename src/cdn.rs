@@ -1,12 +1,16 @@
-use std::{
-    env,
-    marker::PhantomData,
-    sync::{Arc, Mutex},
-};
-
-use redis::Connection;
+use std::{marker::PhantomData, sync::Arc};
 
-use crate::{cache::Cache, config::CdnConfig, error, info, storage::Storage};
+use crate::{
+    cache::Cache,
+    coalesce::SingleFlight,
+    config::CdnConfig,
+    processing::ImagePool,
+    rest::{
+        read::{FetchError, Rendered},
+        Resource,
+    },
+    storage::Storage,
+};
 
 #[derive(Clone)]
 pub struct Disconnected;
@@ -17,8 +21,12 @@ pub struct Connected;
 pub struct Cdn<State = Disconnected> {
     pub storage: Storage,
     pub cache: Cache,
+    pub pool: ImagePool,
+    /// Renders of images that are currently in flight
+    pub renders: Arc<SingleFlight<Rendered, FetchError>>,
+    /// The kinds of resources that are served
+    pub resources: Vec<Resource>,
     pub config: CdnConfig,
-    redis: Option<Arc<Mutex<Connection>>>,
     state: PhantomData<State>,
 }
 
@@ -27,43 +35,33 @@ impl Cdn<Disconnected> {
         Self {
             storage,
             cache,
+            pool: ImagePool::new(&config.image_pool),
+            renders: Arc::new(SingleFlight::new()),
+            resources: config
+                .resources
+                .iter()
+                .cloned()
+                .map(Resource::new)
+                .collect(),
             config,
-            redis: None,
             state: PhantomData::<Disconnected>,
         }
     }
 
+    /// Starts connecting to the cache in the background.
+    ///
+    /// Has to be called from within a tokio runtime.
     pub fn connect(self) -> Cdn<Connected> {
-        let redis_host = env::var("REDIS_HOST").unwrap_or("redis://127.0.0.1".to_string());
-
-        let redis_client = redis::Client::open(redis_host).unwrap_or_else(|why| {
-            error!("Could not connect to redis: {}", why.to_string());
-        });
-
-        let timeout = std::time::Duration::from_secs(10);
-
-        let redis = redis_client
-            .get_connection_with_timeout(timeout)
-            .unwrap_or_else(|_| {
-                error!("Could not connect to redis: timeout");
-            });
-
-        info!("Successfully connected to redis");
+        self.cache.connect();
 
         Cdn {
             storage: self.storage,
             cache: self.cache,
+            pool: self.pool,
+            renders: self.renders,
+            resources: self.resources,
             config: self.config,
-            redis: Some(Arc::new(Mutex::new(redis))),
             state: PhantomData::<Connected>,
         }
     }
 }
-
-impl Cdn<Connected> {
-    pub fn redis(&self) -> Arc<Mutex<Connection>> {
-        self.redis
-            .clone()
-            .expect("Redis should always be of type Some when Cdn is Connected")
-    }
-}
@@ -0,0 +1,68 @@
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+type Flight<T, E> = Shared<BoxFuture<'static, Result<T, E>>>;
+
+/// Deduplicates concurrent work by key: while a future for a key is in flight,
+/// everyone else asking for the same key waits for its result instead of
+/// starting their own.
+pub struct SingleFlight<T, E> {
+    inflight: Mutex<HashMap<String, Flight<T, E>>>,
+}
+
+impl<T, E> Default for SingleFlight<T, E> {
+    fn default() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T, E> SingleFlight<T, E>
+where
+    T: Clone + Send + Sync + 'static,
+    E: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the future created by `work`, unless one is already in flight for `key`,
+    /// in which case its result is returned instead.
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+    {
+        let flight = {
+            let mut inflight = self.inflight.lock().unwrap();
+
+            match inflight.get(key) {
+                Some(flight) => flight.clone(),
+                None => {
+                    let flight = work().boxed().shared();
+                    inflight.insert(key.to_string(), flight.clone());
+                    flight
+                }
+            }
+        };
+
+        let result = flight.clone().await;
+
+        let mut inflight = self.inflight.lock().unwrap();
+
+        // A new flight may have started for the key in the meantime
+        if inflight
+            .get(key)
+            .is_some_and(|current| current.ptr_eq(&flight))
+        {
+            inflight.remove(key);
+        }
+
+        result
+    }
+}
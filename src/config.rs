@@ -5,6 +5,7 @@ use std::{
 };
 
 use anyhow::Result;
+use image::ImageFormat;
 use serde::{Deserialize, Serialize};
 
 use crate::error;
@@ -23,16 +24,670 @@ impl FirewallConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    #[default]
+    Filesystem,
+    S3,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Custom endpoint for S3-compatible stores such as MinIO.
+    pub endpoint: Option<String>,
+    /// Falls back to the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    /// environment variables if not set.
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: StorageBackendKind,
+    pub s3: Option<S3Config>,
+    /// Number of previous versions kept for singleton resources
+    #[serde(default = "default_history")]
+    pub history: usize,
+    /// Where the metadata index of stored resources is kept
+    #[serde(default = "default_index_path")]
+    pub index_path: String,
+    /// Free space in bytes below which the service reports itself as not ready
+    #[serde(default = "default_min_free_space")]
+    pub min_free_space: u64,
+}
+
+fn default_history() -> usize {
+    5
+}
+
+fn default_index_path() -> String {
+    "./index".to_string()
+}
+
+fn default_min_free_space() -> u64 {
+    256 * 1024 * 1024
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackendKind::default(),
+            s3: None,
+            history: default_history(),
+            index_path: default_index_path(),
+            min_free_space: default_min_free_space(),
+        }
+    }
+}
+
+impl StorageConfig {
+    fn validate(&self) {
+        if self.backend == StorageBackendKind::S3 && self.s3.is_none() {
+            error!("The s3 storage backend requires a [storage.s3] section.");
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImagePoolConfig {
+    /// Number of images processed at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Number of images that may wait for processing before requests are rejected
+    #[serde(default = "default_queue_limit")]
+    pub queue_limit: usize,
+    /// Seconds sent in the `Retry-After` header when the pool is saturated
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|parallelism| parallelism.get())
+        .unwrap_or(4)
+}
+
+fn default_queue_limit() -> usize {
+    64
+}
+
+fn default_retry_after() -> u64 {
+    1
+}
+
+impl Default for ImagePoolConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: default_concurrency(),
+            queue_limit: default_queue_limit(),
+            retry_after: default_retry_after(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    #[default]
+    Redis,
+    Memory,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheCompression {
+    #[default]
+    None,
+    Deflate,
+}
+
+/// Overrides the TTL of cached images of a resource, a size, or both.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheRule {
+    /// Name of the resource the rule applies to, any resource if not set
+    pub resource: Option<String>,
+    /// Size the rule applies to, any size if not set
+    pub size: Option<u32>,
+    /// Seconds images matching the rule are kept in the cache
+    pub ttl: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backend: CacheBackendKind,
+    /// Maximum size of all cached values in bytes, for the memory backend
+    #[serde(default = "default_memory_capacity")]
+    pub memory_capacity: usize,
+    /// Maximum size in bytes of the in-process cache in front of the backend, 0 disables it
+    #[serde(default = "default_l1_capacity")]
+    pub l1_capacity: usize,
+    /// Seconds images are kept in the cache, unless a rule says otherwise
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: u64,
+    /// TTL overrides, the first matching rule applies
+    #[serde(default)]
+    pub rules: Vec<CacheRule>,
+    /// Sizes that are always rendered from storage instead of being cached
+    #[serde(default)]
+    pub skip_sizes: Vec<u32>,
+    /// Largest rendered image in bytes that is still cached
+    #[serde(default = "default_max_object_size")]
+    pub max_object_size: usize,
+    /// How images are compressed in the cache backend
+    #[serde(default)]
+    pub compression: CacheCompression,
+}
+
+fn default_memory_capacity() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_l1_capacity() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_cache_ttl() -> u64 {
+    60 * 5
+}
+
+fn default_max_object_size() -> usize {
+    8 * 1024 * 1024
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            backend: CacheBackendKind::default(),
+            memory_capacity: default_memory_capacity(),
+            l1_capacity: default_l1_capacity(),
+            ttl: default_cache_ttl(),
+            rules: Vec::new(),
+            skip_sizes: Vec::new(),
+            max_object_size: default_max_object_size(),
+            compression: CacheCompression::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RedisConfig {
+    /// Time after which a query is given up on, and the request is served from storage
+    #[serde(default = "default_redis_timeout")]
+    pub timeout_ms: u64,
+    /// Number of consecutive failures after which Redis is no longer queried
+    #[serde(default = "default_breaker_threshold")]
+    pub breaker_threshold: u32,
+    /// Seconds to wait before querying Redis again after it failed
+    #[serde(default = "default_breaker_cooldown")]
+    pub breaker_cooldown: u64,
+}
+
+fn default_redis_timeout() -> u64 {
+    100
+}
+
+fn default_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_breaker_cooldown() -> u64 {
+    30
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: default_redis_timeout(),
+            breaker_threshold: default_breaker_threshold(),
+            breaker_cooldown: default_breaker_cooldown(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TracingConfig {
+    /// Whether spans are exported over OTLP
+    #[serde(default)]
+    pub enabled: bool,
+    /// OTLP/HTTP endpoint of the collector traces are sent to
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Share of new traces that are sampled, between 0 and 1. Traces started by a
+    /// caller follow the caller's decision.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+}
+
+fn default_service_name() -> String {
+    "rs-cdn".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: default_otlp_endpoint(),
+            service_name: default_service_name(),
+            sample_ratio: default_sample_ratio(),
+        }
+    }
+}
+
+/// Formats images of a resource may be uploaded in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+}
+
+impl InputFormat {
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            Self::Png => ImageFormat::Png,
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Webp => ImageFormat::WebP,
+            Self::Gif => ImageFormat::Gif,
+        }
+    }
+}
+
+/// Formats images of a resource may be served in besides PNG, through their
+/// extension or the `Accept` header of requests for PNG.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    Avif,
+}
+
+/// Quality of the lossy formats images of a resource are served in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct QualityConfig {
+    /// Quality of WebP images from 0 to 100
+    #[serde(default = "default_webp_quality")]
+    pub webp: u8,
+    /// Whether WebP images are lossless, which ignores `webp`
+    #[serde(default)]
+    pub webp_lossless: bool,
+    /// Quality of AVIF images from 1 to 100
+    #[serde(default = "default_avif_quality")]
+    pub avif: u8,
+}
+
+fn default_webp_quality() -> u8 {
+    80
+}
+
+fn default_avif_quality() -> u8 {
+    70
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            webp: default_webp_quality(),
+            webp_lossless: false,
+            avif: default_avif_quality(),
+        }
+    }
+}
+
+/// How uploads are cropped to the aspect ratio of a resource.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CropMode {
+    /// The largest region in the center of the image
+    #[default]
+    Center,
+    /// The largest region with the most detail
+    Entropy,
+    /// The whole image, padded with transparency
+    Contain,
+}
+
+/// Ratio of the width to the height of the images of a resource, written as `16:9`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct AspectRatio {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl AspectRatio {
+    pub const SQUARE: Self = Self {
+        width: 1,
+        height: 1,
+    };
+
+    /// Height of an image of `width` pixels, at least one pixel.
+    pub fn height_for(&self, width: u32) -> u32 {
+        let height =
+            (width as u64 * self.height as u64 + self.width as u64 / 2) / self.width as u64;
+
+        (height as u32).max(1)
+    }
+
+    /// Width of an image of `height` pixels, at least one pixel.
+    pub fn width_for(&self, height: u32) -> u32 {
+        let width =
+            (height as u64 * self.width as u64 + self.height as u64 / 2) / self.height as u64;
+
+        (width as u32).max(1)
+    }
+}
+
+impl Default for AspectRatio {
+    fn default() -> Self {
+        Self::SQUARE
+    }
+}
+
+impl TryFrom<String> for AspectRatio {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parsed = value.split_once(':').and_then(|(width, height)| {
+            Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+        });
+
+        match parsed {
+            Some((width, height)) if width > 0 && height > 0 => Ok(Self { width, height }),
+            _ => Err(format!(
+                "Invalid aspect ratio \"{value}\", expected e.g. \"16:9\""
+            )),
+        }
+    }
+}
+
+impl From<AspectRatio> for String {
+    fn from(ratio: AspectRatio) -> Self {
+        ratio.to_string()
+    }
+}
+
+impl std::fmt::Display for AspectRatio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.width, self.height)
+    }
+}
+
+/// A kind of resource, served under its own route.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResourceConfig {
+    /// Name used in storage paths, cache keys and metrics
+    pub name: String,
+    /// Path prefix the resource is served under, the name if not set
+    pub route: Option<String>,
+    /// Whether an id holds a single image, replaced by every upload
+    #[serde(default = "default_singleton")]
+    pub singleton: bool,
+    /// Most items an id of a non-singleton resource may hold, unlimited if not set
+    pub max_items: Option<usize>,
+    /// Formats images may be uploaded in
+    #[serde(default = "default_input_formats")]
+    pub formats: Vec<InputFormat>,
+    /// Formats images may be served in besides PNG
+    #[serde(default = "default_output_formats")]
+    pub output_formats: Vec<OutputFormat>,
+    #[serde(default)]
+    pub quality: QualityConfig,
+    /// Ratio of the width to the height of images, square if not set
+    #[serde(default)]
+    pub aspect_ratio: AspectRatio,
+    /// Widths images may be requested in
+    #[serde(default = "default_sizes")]
+    pub sizes: Vec<u32>,
+    /// Size of images requested without a size
+    #[serde(default = "default_size")]
+    pub default_size: u32,
+    /// Largest size animations may be requested in as GIF
+    #[serde(default = "default_size")]
+    pub max_animated_size: u32,
+    /// Largest size animations may be requested in as WebP, which is much cheaper to encode
+    #[serde(default = "default_max_animated_webp_size")]
+    pub max_animated_webp_size: u32,
+    /// Whether animated uploads stay animated, otherwise only their first frame is kept
+    #[serde(default = "default_animated")]
+    pub animated: bool,
+    /// Largest upload in bytes
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: usize,
+    #[serde(default)]
+    pub crop: CropMode,
+}
+
+fn default_singleton() -> bool {
+    true
+}
+
+fn default_input_formats() -> Vec<InputFormat> {
+    vec![
+        InputFormat::Png,
+        InputFormat::Jpeg,
+        InputFormat::Webp,
+        InputFormat::Gif,
+    ]
+}
+
+fn default_output_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Webp, OutputFormat::Avif]
+}
+
+fn default_sizes() -> Vec<u32> {
+    vec![128, 256, 512, 1024, 2048]
+}
+
+fn default_size() -> u32 {
+    256
+}
+
+fn default_max_animated_webp_size() -> u32 {
+    1024
+}
+
+fn default_animated() -> bool {
+    true
+}
+
+fn default_max_upload_size() -> usize {
+    20 * 1024 * 1024
+}
+
+impl ResourceConfig {
+    /// A resource with the default settings.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            route: None,
+            singleton: default_singleton(),
+            max_items: None,
+            formats: default_input_formats(),
+            output_formats: default_output_formats(),
+            quality: QualityConfig::default(),
+            aspect_ratio: AspectRatio::default(),
+            sizes: default_sizes(),
+            default_size: default_size(),
+            max_animated_size: default_size(),
+            max_animated_webp_size: default_max_animated_webp_size(),
+            animated: default_animated(),
+            max_upload_size: default_max_upload_size(),
+            crop: CropMode::default(),
+        }
+    }
+
+    fn validate(&self) {
+        let name = &self.name;
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_'))
+        {
+            error!("Resource name \"{name}\" may only contain a-z, 0-9, - and _.");
+        }
+
+        let route = self.route();
+
+        if route.is_empty() || route.contains(['/', '{', '}']) {
+            error!("Route \"{route}\" of resource {name} has to be a single path segment without {{ or }}.");
+        }
+
+        if RESERVED_ROUTES.contains(&self.route()) {
+            error!(
+                "Resource {name} cannot be served under the reserved route \"{}\".",
+                self.route()
+            );
+        }
+
+        if self.singleton && self.max_items.is_some() {
+            error!("Resource {name} is a singleton, so it cannot have max_items.");
+        }
+
+        if self.formats.is_empty() {
+            error!("Resource {name} has to accept at least one format.");
+        }
+
+        if !self.sizes.contains(&self.default_size) {
+            error!("The default size of resource {name} is not one of its sizes.");
+        }
+
+        let max_sizes = [self.max_animated_size, self.max_animated_webp_size];
+
+        if self
+            .sizes
+            .iter()
+            .chain(&max_sizes)
+            .any(|size| !(1..=MAX_SIZE).contains(size))
+        {
+            error!("The sizes of resource {name} have to be between 1 and {MAX_SIZE}.");
+        }
+
+        for &size in &self.sizes {
+            let height = self.aspect_ratio.height_for(size);
+
+            if height > MAX_SIZE {
+                error!(
+                    "Images of resource {name} would be {height} pixels high at size {size}, \
+                     more than {MAX_SIZE}."
+                );
+            }
+        }
+
+        if self.quality.webp > 100 || !(1..=100).contains(&self.quality.avif) {
+            error!("The quality of resource {name} is out of range.");
+        }
+    }
+
+    /// Path prefix the resource is served under.
+    pub fn route(&self) -> &str {
+        self.route
+            .as_deref()
+            .unwrap_or(&self.name)
+            .trim_matches('/')
+    }
+}
+
+/// Largest width and height images can be served in.
+const MAX_SIZE: u32 = 4096;
+
+/// Routes that are taken by the service itself.
+const RESERVED_ROUTES: [&str; 6] = ["health", "healthz", "readyz", "cache", "metrics", "admin"];
+
+fn default_resources() -> Vec<ResourceConfig> {
+    vec![
+        ResourceConfig::new("avatars"),
+        ResourceConfig {
+            animated: false,
+            max_upload_size: 10 * 1024 * 1024,
+            ..ResourceConfig::new("icons")
+        },
+    ]
+}
+
+fn validate_resources(resources: &[ResourceConfig]) {
+    for (index, resource) in resources.iter().enumerate() {
+        resource.validate();
+
+        for other in &resources[..index] {
+            if other.name == resource.name {
+                error!("Resource {} is defined more than once.", resource.name);
+            }
+
+            if other.route() == resource.route() {
+                error!(
+                    "Resources {} and {} are served under the same route.",
+                    other.name, resource.name
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CdnConfig {
     pub storage_path: Option<String>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub image_pool: ImagePoolConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub redis: RedisConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    /// The kinds of resources that are served, avatars and icons if not set
+    #[serde(default = "default_resources")]
+    pub resources: Vec<ResourceConfig>,
     pub firewall: FirewallConfig,
 }
 
+impl Default for CdnConfig {
+    fn default() -> Self {
+        Self {
+            storage_path: None,
+            storage: StorageConfig::default(),
+            image_pool: ImagePoolConfig::default(),
+            cache: CacheConfig::default(),
+            redis: RedisConfig::default(),
+            tracing: TracingConfig::default(),
+            resources: default_resources(),
+            firewall: FirewallConfig::default(),
+        }
+    }
+}
+
 pub fn get_config() -> Result<CdnConfig> {
     let config_path = config_location().join("config.toml");
     let config: CdnConfig = confy::load_path(config_path)?;
     config.firewall.validate();
+    config.storage.validate();
+    validate_resources(&config.resources);
     Ok(config)
 }
 
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::crop::Crop;
+
+/// Everything known about a stored resource.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceMetadata {
+    pub resource: String,
+    pub id: String,
+    /// SHA1 hash of the uploaded file
+    pub hash: String,
+    /// The file the resource is served under
+    pub filename: String,
+    /// Format of the uploaded file
+    pub format: String,
+    /// Dimensions of the uploaded file, before cropping
+    pub width: u32,
+    pub height: u32,
+    pub animated: bool,
+    pub frame_count: u32,
+    /// Size of the uploaded file in bytes
+    pub size: u64,
+    /// Upload time in seconds since the unix epoch
+    pub uploaded_at: u64,
+    /// All files that belong to the resource
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// How the upload was cropped, not recorded for older resources
+    #[serde(default)]
+    pub crop: Option<Crop>,
+}
+
+/// Key in the state tree marking that the indexed items were recorded in storage.
+const ITEMS_MIGRATED_KEY: &str = "items-migrated";
+
+/// An embedded index of the metadata of every stored resource, keyed by
+/// `{resource}/{id}/{hash}`.
+#[derive(Clone)]
+pub struct MetadataIndex {
+    db: sled::Db,
+    /// Holds what the instance did to its index, apart from the metadata itself
+    state: sled::Tree,
+}
+
+impl MetadataIndex {
+    pub fn open(path: &str) -> Result<Self> {
+        let db = sled::open(path)?;
+        let state = db.open_tree("state")?;
+
+        Ok(Self { db, state })
+    }
+
+    fn key(resource: &str, id: &str, hash: &str) -> String {
+        format!("{resource}/{id}/{hash}")
+    }
+
+    pub fn insert(&self, metadata: &ResourceMetadata) -> Result<()> {
+        let key = Self::key(&metadata.resource, &metadata.id, &metadata.hash);
+
+        self.db.insert(key, serde_json::to_vec(metadata)?)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, resource: &str, id: &str, hash: &str) -> Result<Option<ResourceMetadata>> {
+        match self.db.get(Self::key(resource, id, hash))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the metadata of every resource stored for `id`.
+    pub fn list(&self, resource: &str, id: &str) -> Result<Vec<ResourceMetadata>> {
+        self.db
+            .scan_prefix(format!("{resource}/{id}/"))
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    /// Returns the metadata of every indexed resource.
+    pub fn all(&self) -> Result<Vec<ResourceMetadata>> {
+        self.db
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    pub fn items_migrated(&self) -> Result<bool> {
+        Ok(self.state.contains_key(ITEMS_MIGRATED_KEY)?)
+    }
+
+    pub fn mark_items_migrated(&self) -> Result<()> {
+        self.state.insert(ITEMS_MIGRATED_KEY, &[])?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, resource: &str, id: &str, hash: &str) -> Result<()> {
+        self.db.remove(Self::key(resource, id, hash))?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
@@ -1,10 +1,18 @@
 use cdn::Cdn;
 
+pub mod access_log;
+pub mod breaker;
 pub mod cache;
 pub mod cdn;
+pub mod coalesce;
 pub mod config;
+pub mod index;
+pub mod metrics;
+pub mod processing;
+pub mod redis_pool;
 pub mod rest;
 pub mod storage;
+pub mod telemetry;
 
 #[macro_use]
 pub mod macros;
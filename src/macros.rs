@@ -5,6 +5,14 @@ pub fn strip_colors(input: &str) -> String {
     re.replace_all(input, "").to_string()
 }
 
+/// `[{request id}] ` while a request is being handled, so console output can be
+/// matched with the access log. Log files get the id from their encoder.
+pub fn request_id_prefix() -> String {
+    crate::access_log::current_request_id()
+        .map(|request_id| format!("[{request_id}] "))
+        .unwrap_or_default()
+}
+
 #[macro_export]
 macro_rules! error {
     ($($message:tt)*) => ({
@@ -13,7 +21,12 @@ macro_rules! error {
 
         let formatted = format!("{}", format_args!($($message)*));
         log::error!("{}", $crate::macros::strip_colors(&formatted));
-        eprintln!("{} {}", "[ERROR]".truecolor(RED.0, RED.1, RED.2), format_args!($($message)*));
+        eprintln!(
+            "{} {}{}",
+            "[ERROR]".truecolor(RED.0, RED.1, RED.2),
+            $crate::macros::request_id_prefix(),
+            format_args!($($message)*)
+        );
         std::process::exit(1);
     })
 }
@@ -24,7 +37,12 @@ macro_rules! info {
         use colored::Colorize;
         let formatted = format!("{}", format_args!($($message)*));
         log::info!("{}", $crate::macros::strip_colors(&formatted));
-        println!("{} {}", "[INFO]".blue(), format_args!($($message)*));
+        println!(
+            "{} {}{}",
+            "[INFO]".blue(),
+            $crate::macros::request_id_prefix(),
+            format_args!($($message)*)
+        );
     })
 }
 
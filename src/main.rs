@@ -3,7 +3,7 @@ extern crate rs_cdn;
 
 use anyhow::Result;
 use colored::Colorize;
-use rs_cdn::config;
+use rs_cdn::{access_log, config, telemetry};
 use rs_cdn::{cdn::Cdn, rest};
 use std::env;
 use std::net::SocketAddr;
@@ -17,11 +17,11 @@ use rs_cdn::storage::Storage;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    match log4rs::init_file("log4rs.yaml", Default::default()) {
+    match log4rs::init_file("log4rs.yaml", access_log::deserializers()) {
         Ok(_) => (),
         Err(e) => {
             eprintln!("Error initializing logger: {}", e);
-            return Err(e.into());
+            return Err(e);
         }
     }
 
@@ -61,22 +61,45 @@ async fn main() -> Result<()> {
         );
     }
 
-    let storage_path = config
-        .storage_path
-        .clone()
-        .unwrap_or("./uploads".to_string());
+    let tracer_provider = if config.tracing.enabled {
+        let provider = telemetry::init(&config.tracing)
+            .unwrap_or_else(|why| error!("Could not initialize tracing: {}", why));
 
-    let storage = Storage::new(&storage_path);
-    let cache = Cache::new();
+        info!("Tracing: Exporting spans to {}", config.tracing.endpoint);
+
+        Some(provider)
+    } else {
+        None
+    };
+
+    let storage = Storage::from_config(&config)
+        .unwrap_or_else(|why| error!("Could not initialize storage: {}", why));
+
+    match storage.recover() {
+        Ok(0) => (),
+        Ok(removed) => info!("Removed {} leftover staging file(s)", removed),
+        Err(why) => error!("Could not recover storage: {}", why),
+    }
+
+    match storage.migrate_items() {
+        Ok(0) => (),
+        Ok(migrated) => info!("Recorded {} indexed item(s) in storage", migrated),
+        Err(why) => error!("Could not record indexed items in storage: {}", why),
+    }
+
+    let cache = Cache::from_config(&config)
+        .unwrap_or_else(|why| error!("Could not initialize cache: {}", why));
     let cdn = Arc::new(Cdn::new(storage, cache, config).connect());
 
     HttpServer::new(move || {
         let cors = Cors::default().allow_any_origin();
+        let resources = cdn.resources.clone();
 
         App::new()
             .wrap(cors)
+            .wrap_fn(rest::observe)
             .app_data(web::Data::new(cdn.clone()))
-            .configure(rest::configure_routes)
+            .configure(|cfg| rest::configure_routes(cfg, &resources))
     })
     .bind(address)
     .unwrap_or_else(|why| error!("Can't bind to {:?}: {}", address, why))
@@ -84,5 +107,11 @@ async fn main() -> Result<()> {
     .await
     .expect("Failed to run HttpServer");
 
+    if let Some(provider) = tracer_provider {
+        if let Err(why) = provider.shutdown() {
+            log::warn!("Failed to flush remaining spans: {why}");
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,160 @@
+use std::{sync::LazyLock, time::Duration};
+
+use actix_web::dev::ServiceResponse;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::rest::resource_from_request;
+
+/// Metrics of the whole process, exposed in the Prometheus text format on `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub cache_lookups: IntCounterVec,
+    pub render_duration: HistogramVec,
+    pub upload_bytes: Histogram,
+    pub signature_failures: IntCounterVec,
+    pub firewall_rejections: IntCounter,
+    pub redis_errors: IntCounterVec,
+    pub storage_bytes_written: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("rs_cdn".to_string()), None).expect("Invalid metrics prefix");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Handled HTTP requests"),
+            &["route", "resource", "status"],
+        )
+        .unwrap();
+
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["route", "resource", "status"],
+        )
+        .unwrap();
+
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "Lookups of rendered images per cache tier",
+            ),
+            &["tier", "result"],
+        )
+        .unwrap();
+
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "render_duration_seconds",
+                "Time taken to resize and encode an image",
+            ),
+            &["format", "size"],
+        )
+        .unwrap();
+
+        let upload_bytes = Histogram::with_opts(
+            HistogramOpts::new("upload_bytes", "Size of uploaded images")
+                .buckets(exponential_buckets(16.0 * 1024.0, 4.0, 7).unwrap()),
+        )
+        .unwrap();
+
+        let signature_failures = IntCounterVec::new(
+            Opts::new(
+                "signature_failures_total",
+                "Requests with an invalid signature",
+            ),
+            &["action"],
+        )
+        .unwrap();
+
+        let firewall_rejections = IntCounter::new(
+            "firewall_rejections_total",
+            "Requests from addresses that aren't trusted",
+        )
+        .unwrap();
+
+        let redis_errors = IntCounterVec::new(
+            Opts::new("redis_errors_total", "Failed Redis queries"),
+            &["kind"],
+        )
+        .unwrap();
+
+        let storage_bytes_written = IntCounter::new(
+            "storage_bytes_written_total",
+            "Bytes written to the storage backend",
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry
+            .register(Box::new(render_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(upload_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(signature_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(firewall_rejections.clone()))
+            .unwrap();
+        registry.register(Box::new(redis_errors.clone())).unwrap();
+        registry
+            .register(Box::new(storage_bytes_written.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            cache_lookups,
+            render_duration,
+            upload_bytes,
+            signature_failures,
+            firewall_rejections,
+            redis_errors,
+            storage_bytes_written,
+        }
+    }
+
+    /// Records a handled request, labelled by the pattern of the route it matched,
+    /// so ids and hashes don't end up in the labels.
+    pub fn observe_request<B>(&self, response: &ServiceResponse<B>, elapsed: Duration) {
+        let request = response.request();
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let resource = resource_from_request(request)
+            .map_or("none".to_string(), |resource| resource.to_string());
+        let status = response.status();
+        let labels = [route.as_str(), resource.as_str(), status.as_str()];
+
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+
+        if let Err(why) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {why}");
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::{access_log, config::ImagePoolConfig, rest::GenericError};
+
+#[derive(Debug, Clone, Error)]
+pub enum PoolError {
+    #[error("Image processing is at capacity, try again later")]
+    Saturated { retry_after: u64 },
+    #[error("Image processing failed")]
+    TaskFailed,
+}
+
+impl ResponseError for PoolError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Saturated { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TaskFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let Self::Saturated { retry_after } = self {
+            response.append_header(("Retry-After", retry_after.to_string()));
+        }
+
+        response.json(GenericError {
+            error: self.to_string(),
+        })
+    }
+}
+
+/// A bounded pool for CPU heavy image decoding and encoding, which would
+/// otherwise block the actix workers.
+///
+/// At most `concurrency` tasks run at once, and at most `queue_limit` more
+/// wait for a free slot. Anything beyond that is rejected right away.
+#[derive(Clone)]
+pub struct ImagePool {
+    semaphore: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+    retry_after: u64,
+}
+
+/// Releases a reserved slot of the pool when dropped.
+struct PendingGuard(Arc<AtomicUsize>);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ImagePool {
+    pub fn new(config: &ImagePoolConfig) -> Self {
+        let concurrency = config.concurrency.max(1);
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: concurrency + config.queue_limit,
+            retry_after: config.retry_after,
+        }
+    }
+
+    /// Whether new tasks are currently rejected.
+    pub fn is_saturated(&self) -> bool {
+        self.pending.load(Ordering::Acquire) >= self.capacity
+    }
+
+    pub async fn run<F, T>(&self, task: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.pending.fetch_add(1, Ordering::AcqRel) >= self.capacity {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+
+            return Err(PoolError::Saturated {
+                retry_after: self.retry_after,
+            });
+        }
+
+        let _guard = PendingGuard(self.pending.clone());
+
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| PoolError::TaskFailed)?;
+
+        // Spans and log lines of the task belong to the request that queued it
+        let span = tracing::Span::current();
+        let request_id = access_log::current_request_id();
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+            access_log::sync_scope(request_id, task)
+        })
+        .await
+        .map_err(|_| PoolError::TaskFailed)
+    }
+}
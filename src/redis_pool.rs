@@ -0,0 +1,134 @@
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use redis::{aio::ConnectionManager, Client, RedisResult};
+
+use crate::{
+    breaker::{BreakerState, CircuitBreaker},
+    config::RedisConfig,
+    info,
+    metrics::METRICS,
+};
+
+/// Backoff used by the connection manager when an established connection is lost.
+const RETRY_EXPONENT_BASE: u64 = 2;
+const RETRY_FACTOR: u64 = 100;
+const RETRY_ATTEMPTS: usize = 6;
+
+/// Bounds of the delay between attempts to establish the first connection.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A multiplexed, automatically reconnecting connection to Redis that can be
+/// shared by all workers.
+///
+/// The first connection is established in the background, so Redis being
+/// unavailable at startup doesn't prevent the service from starting.
+/// Queries are bounded by a timeout, and a circuit breaker stops sending
+/// them while Redis keeps failing.
+#[derive(Clone)]
+pub struct RedisPool {
+    client: Client,
+    manager: Arc<OnceLock<ConnectionManager>>,
+    breaker: Arc<CircuitBreaker>,
+    timeout: Duration,
+}
+
+impl RedisPool {
+    pub fn new(redis_host: &str, config: &RedisConfig) -> Result<Self> {
+        Ok(Self {
+            client: Client::open(redis_host)?,
+            manager: Arc::new(OnceLock::new()),
+            breaker: Arc::new(CircuitBreaker::new(
+                config.breaker_threshold,
+                Duration::from_secs(config.breaker_cooldown),
+            )),
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    /// Keeps trying to connect, with an exponential backoff, until it succeeds.
+    ///
+    /// Once connected, the connection manager takes care of reconnecting.
+    pub fn connect_in_background(&self) {
+        let pool = self.clone();
+
+        tokio::spawn(async move {
+            let mut delay = INITIAL_RETRY_DELAY;
+
+            loop {
+                match ConnectionManager::new_with_backoff(
+                    pool.client.clone(),
+                    RETRY_EXPONENT_BASE,
+                    RETRY_FACTOR,
+                    RETRY_ATTEMPTS,
+                )
+                .await
+                {
+                    Ok(manager) => {
+                        let _ = pool.manager.set(manager);
+                        info!("Successfully connected to redis");
+                        break;
+                    }
+                    Err(why) => {
+                        log::warn!("Could not connect to redis, retrying in {delay:?}: {why}");
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns a handle to the connection, or `None` if it hasn't been established yet.
+    pub fn connection(&self) -> Option<ConnectionManager> {
+        self.manager.get().cloned()
+    }
+
+    /// Runs `query` against Redis, failing fast if it isn't connected, the circuit
+    /// breaker is open or the query doesn't complete within the timeout.
+    pub async fn query<T, F, Fut>(&self, query: F) -> Result<T>
+    where
+        F: FnOnce(ConnectionManager) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let Some(con) = self.connection() else {
+            record_error("disconnected");
+            return Err(anyhow!("Redis is not connected"));
+        };
+
+        if !self.breaker.allow() {
+            record_error("breaker_open");
+            return Err(anyhow!("Redis circuit breaker is open"));
+        }
+
+        match tokio::time::timeout(self.timeout, query(con)).await {
+            Ok(Ok(value)) => {
+                self.breaker.record_success();
+                Ok(value)
+            }
+            Ok(Err(why)) => {
+                self.breaker.record_failure();
+                record_error("query");
+                Err(why.into())
+            }
+            Err(_) => {
+                self.breaker.record_failure();
+                record_error("timeout");
+                Err(anyhow!("Redis query timed out after {:?}", self.timeout))
+            }
+        }
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.state()
+    }
+}
+
+fn record_error(kind: &str) {
+    METRICS.redis_errors.with_label_values(&[kind]).inc();
+}
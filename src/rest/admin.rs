@@ -0,0 +1,99 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    cdn::{Cdn, Connected},
+    metrics::METRICS,
+};
+
+use super::write::{check_firewall, verify_signature_header, UploadError};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Time the signature of a request was made at, in seconds since the unix epoch.
+const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Seconds a signed request is accepted for, so that a captured one can't be replayed
+/// later on.
+const MAX_SIGNATURE_AGE: u64 = 300;
+
+/// Returns the timestamp of a signed request, if it is recent enough.
+pub(super) fn signature_timestamp(req: &HttpRequest) -> Result<u64, UploadError> {
+    let timestamp: u64 = req
+        .headers()
+        .get(TIMESTAMP_HEADER)
+        .ok_or(UploadError::MissingHeader(TIMESTAMP_HEADER))?
+        .to_str()
+        .ok()
+        .and_then(|timestamp| timestamp.parse().ok())
+        .ok_or_else(|| UploadError::BadRequest(format!("Invalid {TIMESTAMP_HEADER} header")))?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| UploadError::InternalError)?
+        .as_secs();
+
+    if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE {
+        return Err(UploadError::Unauthorized("Expired signature"));
+    }
+
+    Ok(timestamp)
+}
+
+#[derive(Deserialize)]
+pub struct KeysQuery {
+    cursor: Option<u64>,
+    count: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct KeysResponse {
+    pub keys: Vec<String>,
+    /// Cursor of the next page, `None` once all keys were listed
+    pub next_cursor: Option<u64>,
+}
+
+/// Lists the keys in the cache backend, one page at a time.
+///
+/// The `X-Signature` header has to carry a signature of `admin:cache-keys:{timestamp}`,
+/// made at most five minutes before or after the `X-Signature-Timestamp` it is sent with.
+pub async fn get_cache_keys(
+    query: web::Query<KeysQuery>,
+    data: web::Data<Arc<Cdn<Connected>>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, UploadError> {
+    check_firewall(&req, &data.config.firewall, "admin")?;
+
+    let timestamp = signature_timestamp(&req)?;
+
+    if !verify_signature_header(&req, &format!("admin:cache-keys:{timestamp}"))? {
+        METRICS
+            .signature_failures
+            .with_label_values(&["admin"])
+            .inc();
+        log::warn!("Got invalid signature for listing cache keys");
+        return Err(UploadError::Unauthorized("Invalid signature"));
+    }
+
+    let cursor = query.cursor.unwrap_or(0);
+    let count = query
+        .count
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    Ok(match data.cache.scan(cursor, count).await {
+        Ok((next_cursor, keys)) => HttpResponse::Ok().json(KeysResponse {
+            keys,
+            next_cursor: Some(next_cursor).filter(|cursor| *cursor != 0),
+        }),
+        Err(why) => HttpResponse::ServiceUnavailable()
+            .json(json!({ "error": "Cache unavailable", "message": why.to_string() })),
+    })
+}
@@ -0,0 +1,157 @@
+use std::{fmt::Display, sync::Arc};
+
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+
+use crate::{
+    breaker::BreakerState,
+    cache::Health,
+    cdn::{Cdn, Connected},
+};
+
+use super::write::load_public_key;
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// The cache is unavailable, requests are served from storage
+    Degraded,
+    /// The service can't handle requests
+    Unavailable,
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<BreakerState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<Health>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Health>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn get_health(data: web::Data<Arc<Cdn<Connected>>>) -> Result<HttpResponse> {
+    let memory = data.cache.memory_health().await;
+
+    Ok(match data.cache.health().await {
+        Ok(health) => HttpResponse::Ok().json(HealthResponse {
+            status: HealthStatus::Ok,
+            circuit_breaker: data.cache.breaker_state(),
+            cache: Some(health),
+            memory,
+            error: None,
+        }),
+        Err(why) => HttpResponse::Ok().json(HealthResponse {
+            status: HealthStatus::Degraded,
+            circuit_breaker: data.cache.breaker_state(),
+            cache: None,
+            memory,
+            error: Some(why.to_string()),
+        }),
+    })
+}
+
+/// Liveness: the process is up and handles requests.
+pub async fn get_healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": HealthStatus::Ok }))
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<E: Display> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(why) => Self {
+                ok: false,
+                error: Some(why.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReadinessChecks {
+    pub cache: Check,
+    pub storage: Check,
+    pub disk_space: Check,
+    pub signing_key: Check,
+    pub image_pool: Check,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: HealthStatus,
+    pub checks: ReadinessChecks,
+}
+
+/// Readiness: the service can handle uploads and renders.
+///
+/// An unreachable cache only degrades the service, since requests are then served
+/// from storage. Every other failed check makes it unavailable.
+pub async fn get_readyz(data: web::Data<Arc<Cdn<Connected>>>) -> Result<HttpResponse> {
+    let cache = Check::from(data.cache.ping().await);
+
+    let storage = data.storage.clone();
+    let min_free_space = data.config.storage.min_free_space;
+
+    let (storage, disk_space) = web::block(move || {
+        let writable = Check::from(storage.check_writable());
+
+        let disk_space = Check::from(match storage.available_space() {
+            Ok(Some(available)) if available < min_free_space => Err(format!(
+                "Only {available} bytes free, at least {min_free_space} are required"
+            )),
+            Ok(_) => Ok(()),
+            Err(why) => Err(why.to_string()),
+        });
+
+        (writable, disk_space)
+    })
+    .await?;
+
+    let signing_key = Check::from(load_public_key().map(|_| ()));
+
+    let image_pool = Check::from(if data.pool.is_saturated() {
+        Err("Image processing is at capacity")
+    } else {
+        Ok(())
+    });
+
+    let ready = storage.ok && disk_space.ok && signing_key.ok && image_pool.ok;
+
+    let status = match (ready, cache.ok) {
+        (false, _) => HealthStatus::Unavailable,
+        (true, false) => HealthStatus::Degraded,
+        (true, true) => HealthStatus::Ok,
+    };
+
+    let response = ReadinessResponse {
+        status,
+        checks: ReadinessChecks {
+            cache,
+            storage,
+            disk_space,
+            signing_key,
+            image_pool,
+        },
+    };
+
+    Ok(if ready {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    })
+}
@@ -1,75 +1,199 @@
+pub mod admin;
+pub mod health;
 pub mod read;
 pub mod write;
 
-use actix_web::{error::ErrorInternalServerError, web, HttpResponse, Result};
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web, HttpRequest, HttpResponse, Result,
+};
+use image::ImageFormat;
 use serde::Serialize;
-use std::{fmt::Display, sync::Arc};
-use strum::{EnumIter, IntoEnumIterator};
+use std::{fmt::Display, future::Future, sync::Arc, time::Instant};
+use tracing::Instrument;
 
 use crate::{
+    access_log,
     cdn::Connected,
-    rest::{read::get_resource, write::push_resource},
-    unwrap_or_return,
+    config::{AspectRatio, CropMode, OutputFormat, QualityConfig, ResourceConfig},
+    metrics::METRICS,
+    rest::{
+        admin::get_cache_keys,
+        health::{get_health, get_healthz, get_readyz},
+        read::{get_resource, get_versions, list_resource},
+        write::{delete_resource, push_resource, rollback_resource},
+    },
+    telemetry,
 };
 
 use super::Cdn;
 
-#[derive(Debug, EnumIter)]
-pub enum Resource {
-    Avatars,
-    Icons,
-}
+/// A kind of resource, as configured in the `resources` section.
+#[derive(Debug, Clone)]
+pub struct Resource(Arc<ResourceConfig>);
 
 impl Resource {
+    pub fn new(config: ResourceConfig) -> Self {
+        Self(Arc::new(config))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Path prefix the resource is served under.
+    pub fn route(&self) -> &str {
+        self.0.route()
+    }
+
     pub fn singleton(&self) -> bool {
-        match self {
-            Self::Avatars => true,
-            Self::Icons => true,
+        self.0.singleton
+    }
+
+    /// Most items an id may hold, if limited.
+    pub fn max_items(&self) -> Option<usize> {
+        match self.0.singleton {
+            true => None,
+            false => self.0.max_items,
         }
     }
-}
 
-impl Display for Resource {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Resource::Avatars => "avatars",
-            Resource::Icons => "icons",
-        })
+    /// Whether images may be uploaded in `format`.
+    pub fn accepts(&self, format: ImageFormat) -> bool {
+        self.0
+            .formats
+            .iter()
+            .any(|accepted| accepted.image_format() == format)
+    }
+
+    pub fn aspect_ratio(&self) -> AspectRatio {
+        self.0.aspect_ratio
     }
-}
 
-impl TryFrom<&str> for Resource {
-    type Error = String;
+    /// Widths images may be requested in.
+    pub fn sizes(&self) -> &[u32] {
+        &self.0.sizes
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        for resource in Resource::iter() {
-            let resource_str = resource.to_string();
+    /// Size of images requested without a size.
+    pub fn default_size(&self) -> u32 {
+        self.0.default_size
+    }
 
-            if resource_str == value {
-                return Ok(resource);
-            }
-        }
+    /// Largest size animations may be requested in as GIF.
+    pub fn max_animated_size(&self) -> u32 {
+        self.0.max_animated_size
+    }
+
+    /// Largest size animations may be requested in as WebP.
+    pub fn max_animated_webp_size(&self) -> u32 {
+        self.0.max_animated_webp_size
+    }
+
+    /// Largest upload in bytes.
+    pub fn max_upload_size(&self) -> usize {
+        self.0.max_upload_size
+    }
+
+    /// Whether animated uploads stay animated. Otherwise only their first frame is kept.
+    pub fn animated(&self) -> bool {
+        self.0.animated
+    }
+
+    pub fn crop(&self) -> CropMode {
+        self.0.crop
+    }
 
-        Err("Unknown resource specified".to_string())
+    /// Whether images may be served in `format` besides PNG.
+    pub fn serves(&self, format: OutputFormat) -> bool {
+        self.0.output_formats.contains(&format)
+    }
+
+    pub fn quality(&self) -> QualityConfig {
+        self.0.quality
     }
 }
 
-fn configure_resource(resource: Resource, cfg: &mut web::ServiceConfig) {
+impl PartialEq for Resource {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for Resource {}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Resolves the resource of the route a request was matched to.
+pub(crate) fn resource_from_request(request: &HttpRequest) -> Option<Resource> {
+    request
+        .app_data::<web::Data<Resource>>()
+        .map(|resource| resource.get_ref().clone())
+}
+
+fn configure_resource(resource: &Resource, cfg: &mut web::ServiceConfig) {
     cfg.service(
-        web::scope(&resource.to_string())
+        web::scope(resource.route())
+            .app_data(web::Data::new(resource.clone()))
             .route(
-                r"{id}/{image_hash:(a_)?[0-9a-fA-F]{40}}.{ext:(png|gif)}",
+                r"{id}/{image_hash:(a_)?[0-9a-f]{40}}.{ext:(png|gif|webp|avif)}",
                 web::get().to(get_resource),
             )
-            .route("{id}", web::post().to(push_resource)),
+            .route("{id}", web::get().to(list_resource))
+            .route("{id}", web::post().to(push_resource))
+            .route("{id}/versions", web::get().to(get_versions))
+            .route("{id}/rollback/{hash}", web::post().to(rollback_resource))
+            .route(
+                r"{id}/{hash:[0-9a-f]{40}}",
+                web::delete().to(delete_resource),
+            ),
     );
 }
 
-pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    configure_resource(Resource::Avatars, cfg);
-    configure_resource(Resource::Icons, cfg);
+/// Registers the routes of every resource, and those of the service itself.
+pub fn configure_routes(cfg: &mut web::ServiceConfig, resources: &[Resource]) {
+    for resource in resources {
+        configure_resource(resource, cfg);
+    }
 
     cfg.route("health", web::get().to(get_health));
+    cfg.route("healthz", web::get().to(get_healthz));
+    cfg.route("readyz", web::get().to(get_readyz));
+    cfg.route("cache/stats", web::get().to(get_cache_stats));
+    cfg.route("metrics", web::get().to(get_metrics));
+    cfg.route("admin/cache/keys", web::get().to(get_cache_keys));
+}
+
+/// Runs every request within its request id and tracing span, and records it in the
+/// metrics and the access log. Registered with [`actix_web::App::wrap_fn`].
+pub fn observe<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let started_at = Instant::now();
+    let request_id = access_log::request_id(&req);
+    let span = telemetry::request_span(&req, &request_id);
+    let response = access_log::scope(request_id.clone(), srv.call(req).instrument(span.clone()));
+
+    async move {
+        let mut response = response.await?;
+        let elapsed = started_at.elapsed();
+
+        telemetry::record_response(&span, &response);
+        METRICS.observe_request(&response, elapsed);
+        access_log::finish(&mut response, &request_id, elapsed);
+
+        Ok(response)
+    }
 }
 
 #[derive(Serialize)]
@@ -77,18 +201,24 @@ pub struct GenericError {
     pub error: String,
 }
 
-async fn get_health(data: web::Data<Arc<Cdn<Connected>>>) -> Result<HttpResponse> {
-    let redis = data.redis();
+/// Runs a storage call on the blocking pool, as backends may do network round-trips
+/// that would otherwise block the worker.
+pub async fn blocking<F, T>(call: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let request_id = access_log::current_request_id();
 
-    let mut con = unwrap_or_return!(
-        redis.lock(),
-        ErrorInternalServerError("Connection error with redis")
-    );
+    web::block(move || access_log::sync_scope(request_id, call)).await?
+}
+
+async fn get_cache_stats(data: web::Data<Arc<Cdn<Connected>>>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(data.cache.stats()))
+}
 
-    Ok(match data.cache.get_redis_health(&mut con) {
-        Ok(health) => HttpResponse::Ok().json(health),
-        Err(why) => HttpResponse::InternalServerError().json(GenericError {
-            error: why.to_string(),
-        }),
-    })
+async fn get_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.encode())
 }
@@ -1,51 +1,248 @@
 use std::{
     io::{BufReader, Cursor},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use actix_web::{
     error::{ErrorBadRequest, ErrorInternalServerError},
-    web, HttpRequest, HttpResponse, Result,
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, ResponseError, Result,
 };
+use bytes::Bytes;
 use image::{
-    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    codecs::gif::{GifEncoder, Repeat},
     codecs::png::PngDecoder,
+    codecs::webp::{WebPEncoder, WebPQuality},
     imageops::FilterType,
-    AnimationDecoder, DynamicImage, Frame, ImageOutputFormat,
+    ColorType, DynamicImage, Frame, ImageOutputFormat,
 };
-use serde::Deserialize;
+use ravif::{Img, RGBA8};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
+    cache::{CacheKey, CacheTier, LOCK_TTL},
     cdn::{Cdn, Connected},
+    config::{OutputFormat, QualityConfig},
+    index::ResourceMetadata,
+    metrics::METRICS,
+    processing::PoolError,
+    storage::{self, Version, MAX_ANIMATION_PIXELS, MAX_FRAME_COUNT},
     unwrap_or_return,
 };
 
-use super::{GenericError, Resource};
+use super::{blocking, Resource};
+
+#[derive(Serialize)]
+pub struct VersionsResponse {
+    pub versions: Vec<Version>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Hash of the last item of the previous page
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ItemsResponse {
+    pub items: Vec<ResourceMetadata>,
+    /// Hash to continue listing after, `None` on the last page
+    pub next: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
 
 #[derive(Debug, Deserialize)]
 pub struct QueryParams {
     size: Option<u32>,
 }
 
-enum ImageFormat {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
     Png,
     Gif,
+    WebP,
+    Avif,
+    AnimatedWebP,
+}
+
+/// A rendered image, and the tier of the cache it was found in if it was
+/// rendered elsewhere. `None` if the image doesn't exist.
+pub type Rendered = Option<(Bytes, Option<CacheTier>)>;
+
+#[derive(Debug, Clone, Error)]
+pub enum FetchError {
+    #[error("Failed to read from storage")]
+    Storage,
+    #[error(transparent)]
+    Pool(#[from] PoolError),
+    #[error(transparent)]
+    Render(#[from] RenderError),
+}
+
+impl ResponseError for FetchError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Storage => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Pool(err) => err.status_code(),
+            Self::Render(err) => err.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::Storage => HttpResponse::InternalServerError().body(self.to_string()),
+            Self::Pool(err) => err.error_response(),
+            Self::Render(err) => err.error_response(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum RenderError {
+    #[error("Failed to create animation decoder")]
+    AnimationDecoder,
+    #[error("Error collecting frames")]
+    Frames,
+    #[error("The animation has too many frames to render at this size")]
+    AnimationTooLarge,
+    #[error("Error encoding frames")]
+    GifEncoder,
+    #[error("Failed to create PNG decoder")]
+    PngDecoder,
+    #[error("Failed to decode PNG")]
+    PngDecode,
+    #[error("Failed to write PNG to buffer")]
+    PngEncode,
+    #[error("Failed to encode WebP")]
+    WebPEncode,
+    #[error("Failed to encode AVIF")]
+    AvifEncode,
+}
+
+impl ResponseError for RenderError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::PngEncode | Self::WebPEncode | Self::AvifEncode => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 impl ImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Png => "png",
+            Self::WebP | Self::AnimatedWebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// Name of the format in cache keys and metrics, which tells still and animated
+    /// WebPs apart.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::AnimatedWebP => "animated_webp",
+            _ => self.extension(),
+        }
+    }
+
     fn content_type(&self) -> &str {
         match self {
             Self::Gif => "image/gif",
             Self::Png => "image/png",
+            Self::WebP | Self::AnimatedWebP => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+
+    /// Extension of the stored image this format is rendered from.
+    fn source_extension(&self) -> &'static str {
+        match self {
+            Self::Gif | Self::AnimatedWebP => "webp",
+            Self::Png | Self::WebP | Self::Avif => "png",
+        }
+    }
+
+    /// Extension of the stored image this format was rendered from before, which
+    /// older uploads only have.
+    fn legacy_source_extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Gif | Self::AnimatedWebP => Some("gif"),
+            Self::Png | Self::WebP | Self::Avif => None,
+        }
+    }
+
+    /// The format configured for `resource` to be served in besides PNG, `None` for
+    /// PNG and GIF, which are always served.
+    fn output_format(&self) -> Option<OutputFormat> {
+        match self {
+            Self::WebP | Self::AnimatedWebP => Some(OutputFormat::Webp),
+            Self::Avif => Some(OutputFormat::Avif),
+            Self::Png | Self::Gif => None,
         }
     }
 
-    fn max_size(&self) -> u32 {
+    /// Largest size images of `resource` may be requested in, in this format.
+    fn max_size(&self, resource: &Resource) -> Option<u32> {
         match self {
-            Self::Gif => MAX_SIZE_GIF,
-            Self::Png => MAX_SIZE_PNG,
+            Self::Gif => Some(resource.max_animated_size()),
+            Self::AnimatedWebP => Some(resource.max_animated_webp_size()),
+            Self::Png | Self::WebP | Self::Avif => None,
         }
     }
+
+    /// Whether the format is picked by the `Accept` header of the request.
+    fn is_negotiated(&self) -> bool {
+        matches!(self, Self::Png | Self::Gif)
+    }
+
+    /// Picks the format a PNG or GIF is served in, preferring AVIF over WebP over the
+    /// requested format among the ones `accept` lists and `resource` is served in.
+    fn negotiate(self, accept: Option<&str>, resource: &Resource) -> Self {
+        let candidates: &[Self] = match self {
+            Self::Png => &[Self::Avif, Self::WebP],
+            Self::Gif => &[Self::AnimatedWebP],
+            _ => &[],
+        };
+
+        let Some(accept) = accept else {
+            return self;
+        };
+
+        let accepted = |media_type: &str| {
+            accept.split(',').any(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let listed = parts.next() == Some(media_type);
+                let refused = parts.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+
+                listed && !refused
+            })
+        };
+
+        candidates
+            .iter()
+            .copied()
+            .find(|format| {
+                format
+                    .output_format()
+                    .is_some_and(|output| resource.serves(output))
+                    && accepted(format.content_type())
+            })
+            .unwrap_or(self)
+    }
 }
 
 impl TryFrom<&str> for ImageFormat {
@@ -55,170 +252,457 @@ impl TryFrom<&str> for ImageFormat {
         match value {
             "gif" => Ok(Self::Gif),
             "png" => Ok(Self::Png),
+            "webp" => Ok(Self::WebP),
+            "avif" => Ok(Self::Avif),
             _ => Err(String::from("Unknown image format")),
         }
     }
 }
 
-const MAX_SIZE_PNG: u32 = 2048;
-const DEFAULT_SIZE: u32 = 256;
-const MAX_SIZE_GIF: u32 = DEFAULT_SIZE;
-const SIZES: [u32; 5] = [128, DEFAULT_SIZE, 512, 1024, MAX_SIZE_PNG];
+/// Speed of the AVIF encoder from 1 to 10, where faster makes slightly larger files.
+const AVIF_SPEED: u8 = 8;
+
+/// How often to check the cache while another instance renders an image.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub async fn get_resource(
-    request: HttpRequest,
     path: web::Path<(String, String, String)>,
+    resource: web::Data<Resource>,
     data: web::Data<Arc<Cdn<Connected>>>,
     query: web::Query<QueryParams>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
-    let uri = request.uri().to_string();
-    let segments: Vec<&str> = uri
-        .split('/')
-        .filter(|segment| !segment.is_empty())
-        .collect();
+    let resource = resource.get_ref().clone();
+    let size = query.size.unwrap_or(resource.default_size());
 
-    let size = query.size.unwrap_or(DEFAULT_SIZE);
-    let resource_type = Resource::try_from(segments[0]);
-
-    if !SIZES.contains(&size) {
+    if !resource.sizes().contains(&size) {
         return Err(ErrorBadRequest("The specified size is not valid"));
     }
 
-    if let Ok(resource) = resource_type {
-        let id = &path.0;
-        let image_hash = &path.1;
-        let ext = &path.2;
-        let filename = format!("{image_hash}.{ext}");
-        let image_format = unwrap_or_return!(
-            ImageFormat::try_from(ext.as_str()),
-            ErrorBadRequest("Invalid image extension")
-        );
-        let max_size = image_format.max_size();
+    let id = &path.0;
+    let image_hash = &path.1;
+    let ext = &path.2;
+    let mut requested_format = unwrap_or_return!(
+        ImageFormat::try_from(ext.as_str()),
+        ErrorBadRequest("Invalid image extension")
+    );
+
+    // The WebP of an animation is animated, its still is only served as PNG or negotiated
+    if requested_format == ImageFormat::WebP && image_hash.starts_with("a_") {
+        requested_format = ImageFormat::AnimatedWebP;
+    }
+
+    // Only PNGs and GIFs are negotiated, other extensions get exactly that format
+    let negotiated = requested_format.is_negotiated();
+    let image_format = match negotiated {
+        true => {
+            let accept = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok());
+            requested_format.negotiate(accept, &resource)
+        }
+        false => requested_format,
+    };
+
+    if let Some(output_format) = image_format.output_format() {
+        if !resource.serves(output_format) {
+            return Err(ErrorBadRequest(format!(
+                "Images of {resource} are not served as {ext}"
+            )));
+        }
+    }
+
+    let filename = format!("{image_hash}.{}", image_format.source_extension());
 
+    if let Some(max_size) = image_format.max_size(&resource) {
         if size > max_size {
             return Err(ErrorBadRequest(format!(
-                "Size of a {ext} image cannot be larger than {max_size}"
+                "Size of a {} image cannot be larger than {max_size}",
+                image_format.name()
             )));
         }
+    }
+
+    let key = CacheKey::new(&resource, id, image_hash, image_format.name(), size);
+    let cdn = data.get_ref();
+
+    let (bytes, origin_status) = match cdn.cache.get(&key).await {
+        Some((bytes, tier)) => (bytes, tier.to_string()),
+        None => {
+            let cdn = cdn.clone();
+            let id = id.clone();
+            let flight_key = key.to_string();
+
+            let fetched = data
+                .renders
+                .run(&flight_key, move || {
+                    fetch(cdn, resource, id, filename, key, image_format, size)
+                })
+                .await?;
+
+            match fetched {
+                Some((bytes, tier)) => (
+                    bytes,
+                    tier.map_or("origin".to_string(), |tier| tier.to_string()),
+                ),
+                None => return Ok(HttpResponse::NotFound().finish()),
+            }
+        }
+    };
+
+    let content_type = image_format.content_type();
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .append_header(("X-Origin-Status", origin_status));
+
+    if negotiated {
+        response.append_header((header::VARY, "Accept"));
+    }
+
+    Ok(response.body(bytes))
+}
+
+/// Renders the image for `key` from storage and caches it.
+///
+/// Concurrent requests for the same key are coalesced by the caller within this
+/// instance. Across instances, a short lock in the cache makes sure only one of them
+/// renders the image, while the others wait for it to show up in the cache.
+#[tracing::instrument(name = "render", skip_all, fields(%key))]
+async fn fetch(
+    cdn: Arc<Cdn<Connected>>,
+    resource: Resource,
+    id: String,
+    filename: String,
+    key: CacheKey,
+    image_format: ImageFormat,
+    size: u32,
+) -> Result<Rendered, FetchError> {
+    if !cdn.cache.is_cacheable(&key) {
+        return render_from_storage(&cdn, resource, &id, &filename, &key, image_format, size).await;
+    }
 
-        let key = format!("{id}:{image_hash}:{ext}:{size}");
-        let cdn = data.get_ref();
+    let token = cdn.cache.try_lock(&key).await;
 
-        let mut is_from_cache = false;
-        let redis = data.redis();
+    if token.is_none() {
+        let deadline = Instant::now() + LOCK_TTL;
 
-        let mut con = unwrap_or_return!(
-            redis.lock(),
-            ErrorInternalServerError("Connection error with redis")
-        );
+        loop {
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
 
-        let image_data = match cdn.cache.get(&mut con, &key) {
-            Some(data) => {
-                is_from_cache = true;
-                Some(data)
+            if let Some((bytes, tier)) = cdn.cache.poll(&key).await {
+                return Ok(Some((bytes, Some(tier))));
             }
-            None => cdn.storage.get(resource, id, &filename),
-        };
 
-        return match image_data {
-            Some(image_data) => {
-                let bytes = if is_from_cache {
-                    image_data
-                } else {
-                    let cursor = Cursor::new(&image_data);
-                    let buf_reader = BufReader::new(cursor);
-
-                    match image_format {
-                        ImageFormat::Gif => {
-                            let decoder = unwrap_or_return!(
-                                GifDecoder::new(buf_reader),
-                                ErrorBadRequest("Failed to create GIF decoder")
-                            );
-                            let frames = decoder.into_frames();
-                            let frames = unwrap_or_return!(
-                                frames.collect_frames(),
-                                ErrorBadRequest("Error collecting frames")
-                            );
-
-                            let mut output_frames = Vec::new();
-                            for frame in frames {
-                                let buffer = frame.clone().into_buffer();
-                                let mut image = DynamicImage::ImageRgba8(buffer.clone());
-                                image = image.resize_exact(size, size, FilterType::Triangle);
-                                output_frames.push(Frame::from_parts(
-                                    image.to_rgba8(),
-                                    0,
-                                    0,
-                                    frame.delay(),
-                                ));
-                            }
-
-                            let mut buffer = Vec::new();
-                            {
-                                let mut gif_encoder = GifEncoder::new_with_speed(&mut buffer, 30);
-                                unwrap_or_return!(
-                                    gif_encoder.set_repeat(Repeat::Infinite),
-                                    ErrorBadRequest("Error encoding frames")
-                                );
-                                unwrap_or_return!(
-                                    gif_encoder.encode_frames(output_frames),
-                                    ErrorBadRequest("Error encoding frames")
-                                );
-                            }
-
-                            unwrap_or_return!(
-                                cdn.cache.put(&mut con, &key, &buffer),
-                                ErrorInternalServerError("Failed to write to cache")
-                            );
-
-                            buffer
-                        }
-                        ImageFormat::Png => {
-                            let decoder = unwrap_or_return!(
-                                PngDecoder::new(buf_reader),
-                                ErrorBadRequest("Failed to create PNG decoder")
-                            );
-                            let mut image = unwrap_or_return!(
-                                DynamicImage::from_decoder(decoder),
-                                ErrorBadRequest("Failed to decode PNG")
-                            );
-
-                            image = image.resize_exact(size, size, FilterType::Triangle);
-
-                            let mut buffer = Vec::new();
-                            unwrap_or_return!(
-                                image.write_to(
-                                    &mut Cursor::new(&mut buffer),
-                                    ImageOutputFormat::Png
-                                ),
-                                ErrorInternalServerError("Failed to write PNG to buffer")
-                            );
-
-                            unwrap_or_return!(
-                                cdn.cache.put(&mut con, &key, &buffer),
-                                ErrorInternalServerError("Failed to write to cache")
-                            );
-
-                            buffer
-                        }
-                    }
-                };
-
-                let content_type = image_format.content_type();
-
-                Ok(HttpResponse::Ok()
-                    .content_type(content_type)
-                    .append_header((
-                        "X-Origin-Status",
-                        if is_from_cache { "cache" } else { "origin" },
-                    ))
-                    .body(bytes))
+            // The holder is done without caching anything, e.g. because the image
+            // doesn't exist or is too large to cache, so there's nothing to wait for
+            if !cdn.cache.is_locked(&key).await {
+                // It may have been cached just before the lock was released
+                if let Some((bytes, tier)) = cdn.cache.poll(&key).await {
+                    return Ok(Some((bytes, Some(tier))));
+                }
+
+                break;
             }
-            None => Ok(HttpResponse::NotFound().finish()),
-        };
+
+            if Instant::now() >= deadline {
+                log::debug!("Gave up waiting for {key} to be rendered elsewhere");
+                break;
+            }
+        }
+    }
+
+    let result =
+        render_from_storage(&cdn, resource, &id, &filename, &key, image_format, size).await;
+
+    if let Some(token) = token {
+        cdn.cache.unlock(&key, &token).await;
+    }
+
+    result
+}
+
+async fn render_from_storage(
+    cdn: &Cdn<Connected>,
+    resource: Resource,
+    id: &str,
+    filename: &str,
+    key: &CacheKey,
+    image_format: ImageFormat,
+    size: u32,
+) -> Result<Rendered, FetchError> {
+    let storage = cdn.storage.clone();
+    let (owned_resource, owned_id, owned_filename) =
+        (resource.clone(), id.to_string(), filename.to_string());
+    let legacy_filename = image_format
+        .legacy_source_extension()
+        .and_then(|extension| {
+            let (stem, _) = filename.rsplit_once('.')?;
+            Some(format!("{stem}.{extension}"))
+        });
+    let image_data = unwrap_or_return!(
+        blocking(move || {
+            let image_data = storage.get(&owned_resource, &owned_id, &owned_filename)?;
+
+            match (image_data, legacy_filename) {
+                (None, Some(legacy_filename)) => {
+                    storage.get(&owned_resource, &owned_id, &legacy_filename)
+                }
+                (image_data, _) => Ok(image_data),
+            }
+        })
+        .await,
+        FetchError::Storage
+    );
+
+    let Some(image_data) = image_data else {
+        return Ok(None);
+    };
+
+    let height = resource.aspect_ratio().height_for(size);
+    let quality = resource.quality();
+
+    let bytes = Bytes::from(
+        cdn.pool
+            .run(move || {
+                let started_at = Instant::now();
+                let rendered = render(image_data, image_format, quality, size, height);
+
+                METRICS
+                    .render_duration
+                    .with_label_values(&[image_format.name(), &size.to_string()])
+                    .observe(started_at.elapsed().as_secs_f64());
+
+                rendered
+            })
+            .await??,
+    );
+
+    // The image can still be served if it couldn't be cached
+    if let Err(why) = cdn.cache.put(key, bytes.clone()).await {
+        log::debug!("Failed to write {key} to cache: {why}");
+    }
+
+    Ok(Some((bytes, None)))
+}
+
+/// Resizes a stored image to `width` by `height`, encoding it in `image_format`.
+///
+/// This is CPU heavy and should be run on the image processing pool.
+fn render(
+    image_data: Vec<u8>,
+    image_format: ImageFormat,
+    quality: QualityConfig,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, RenderError> {
+    let cursor = Cursor::new(&image_data);
+    let buf_reader = BufReader::new(cursor);
+
+    match image_format {
+        ImageFormat::Gif | ImageFormat::AnimatedWebP => {
+            // Animations are stored as lossless WebP, those of older uploads as GIF
+            let source_format = unwrap_or_return!(
+                image::guess_format(&image_data),
+                RenderError::AnimationDecoder
+            );
+            let (_, frames) = unwrap_or_return!(
+                storage::decode_frames(source_format, buf_reader),
+                RenderError::AnimationDecoder
+            );
+
+            // Animations stored as GIF weren't limited when they were uploaded, and the
+            // frames may still be too many to resize to this size
+            let decode_span = tracing::info_span!("decode", format = ?source_format).entered();
+            let max_frames =
+                (MAX_ANIMATION_PIXELS / (width as u64 * height as u64)).min(MAX_FRAME_COUNT as u64);
+            let mut source_pixels = 0;
+            let mut source_frames = Vec::new();
+            for frame in frames {
+                let frame = unwrap_or_return!(frame, RenderError::Frames);
+                source_pixels += frame.buffer().width() as u64 * frame.buffer().height() as u64;
+
+                if source_frames.len() as u64 >= max_frames || source_pixels > MAX_ANIMATION_PIXELS
+                {
+                    return Err(RenderError::AnimationTooLarge);
+                }
+
+                source_frames.push(frame);
+            }
+            decode_span.exit();
+
+            let resize_span = tracing::info_span!("resize", width, height).entered();
+            let mut output_frames = Vec::new();
+            for frame in source_frames {
+                let delay = frame.delay();
+                let image = DynamicImage::ImageRgba8(frame.into_buffer());
+                let image = image.resize_exact(width, height, FilterType::Triangle);
+                output_frames.push(Frame::from_parts(image.to_rgba8(), 0, 0, delay));
+            }
+            resize_span.exit();
+
+            let _encode_span =
+                tracing::info_span!("encode", format = image_format.name()).entered();
+
+            if image_format == ImageFormat::AnimatedWebP {
+                return encode_animated_webp(&output_frames, width, height, quality);
+            }
+
+            let mut buffer = Vec::new();
+            {
+                let mut gif_encoder = GifEncoder::new_with_speed(&mut buffer, 30);
+                unwrap_or_return!(
+                    gif_encoder.set_repeat(Repeat::Infinite),
+                    RenderError::GifEncoder
+                );
+                unwrap_or_return!(
+                    gif_encoder.encode_frames(output_frames),
+                    RenderError::GifEncoder
+                );
+            }
+
+            Ok(buffer)
+        }
+        ImageFormat::Png | ImageFormat::WebP | ImageFormat::Avif => {
+            let decode_span = tracing::info_span!("decode", format = "png").entered();
+            let decoder = unwrap_or_return!(PngDecoder::new(buf_reader), RenderError::PngDecoder);
+            let mut image =
+                unwrap_or_return!(DynamicImage::from_decoder(decoder), RenderError::PngDecode);
+            decode_span.exit();
+
+            image = tracing::info_span!("resize", width, height)
+                .in_scope(|| image.resize_exact(width, height, FilterType::Triangle));
+
+            let _encode_span =
+                tracing::info_span!("encode", format = image_format.extension()).entered();
+            encode_still(&image, image_format, quality)
+        }
     }
+}
+
+/// Encodes frames of `width` by `height` pixels as an animated WebP that loops forever.
+fn encode_animated_webp(
+    frames: &[Frame],
+    width: u32,
+    height: u32,
+    quality: QualityConfig,
+) -> Result<Vec<u8>, RenderError> {
+    let mut config = webp::WebPConfig::new().map_err(|_| RenderError::WebPEncode)?;
+    config.quality = quality.webp.into();
+    config.lossless = quality.webp_lossless.into();
+
+    storage::encode_animated_webp(frames, width, height, &config).map_err(|why| {
+        log::info!("Caught error: {why:?}");
+        RenderError::WebPEncode
+    })
+}
+
+/// Encodes a still image in `image_format`, which can't be animated.
+fn encode_still(
+    image: &DynamicImage,
+    image_format: ImageFormat,
+    quality: QualityConfig,
+) -> Result<Vec<u8>, RenderError> {
+    let mut buffer = Vec::new();
+
+    match image_format {
+        ImageFormat::WebP => {
+            let webp_quality = match quality.webp_lossless {
+                true => WebPQuality::lossless(),
+                false => WebPQuality::lossy(quality.webp),
+            };
+            let rgba = image.to_rgba8();
+
+            unwrap_or_return!(
+                WebPEncoder::new_with_quality(&mut buffer, webp_quality).encode(
+                    &rgba,
+                    rgba.width(),
+                    rgba.height(),
+                    ColorType::Rgba8
+                ),
+                RenderError::WebPEncode
+            );
+        }
+        ImageFormat::Avif => {
+            let rgba = image.to_rgba8();
+            let pixels: Vec<RGBA8> = rgba
+                .pixels()
+                .map(|pixel| RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+                .collect();
+
+            let encoded = unwrap_or_return!(
+                ravif::Encoder::new()
+                    .with_quality(quality.avif.into())
+                    .with_alpha_quality(quality.avif.into())
+                    .with_speed(AVIF_SPEED)
+                    .encode_rgba(Img::new(
+                        pixels.as_slice(),
+                        rgba.width() as usize,
+                        rgba.height() as usize
+                    )),
+                RenderError::AvifEncode
+            );
+
+            buffer = encoded.avif_file;
+        }
+        _ => {
+            unwrap_or_return!(
+                image.write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png),
+                RenderError::PngEncode
+            );
+        }
+    }
+
+    Ok(buffer)
+}
+
+pub async fn get_versions(
+    path: web::Path<String>,
+    resource: web::Data<Resource>,
+    data: web::Data<Arc<Cdn<Connected>>>,
+) -> Result<HttpResponse> {
+    let storage = data.storage.clone();
+    let resource = resource.get_ref().clone();
+    let id = path.into_inner();
+    let versions = unwrap_or_return!(
+        blocking(move || storage.versions(&resource, &id)).await,
+        ErrorInternalServerError("Failed to read versions")
+    );
+
+    Ok(HttpResponse::Ok().json(VersionsResponse { versions }))
+}
+
+/// Lists the items stored for an id, ordered by hash, one page at a time.
+pub async fn list_resource(
+    path: web::Path<String>,
+    resource: web::Data<Resource>,
+    data: web::Data<Arc<Cdn<Connected>>>,
+    query: web::Query<ListQuery>,
+) -> Result<HttpResponse> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let storage = data.storage.clone();
+    let resource = resource.get_ref().clone();
+    let id = path.into_inner();
+    let after = query.after.clone();
+
+    // One more item than requested tells whether there is another page
+    let mut items = unwrap_or_return!(
+        blocking(move || storage.list(&resource, &id, after.as_deref(), limit + 1)).await,
+        ErrorInternalServerError("Failed to list items")
+    );
+
+    let next = match items.len() > limit {
+        true => {
+            items.truncate(limit);
+            items.last().map(|item| item.hash.clone())
+        }
+        false => None,
+    };
 
-    Ok(HttpResponse::NotFound().json(GenericError {
-        error: "Resource not found".to_string(),
-    }))
+    Ok(HttpResponse::Ok().json(ItemsResponse { items, next }))
 }
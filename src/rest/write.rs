@@ -1,26 +1,35 @@
-use actix_multipart::{Multipart, MultipartError};
+use actix_multipart::{Field, Multipart, MultipartError};
 use actix_web::HttpRequest;
 use actix_web::{web, HttpResponse};
 use actix_web::{ResponseError, Result};
 use base64::engine::general_purpose;
 use base64::Engine;
 use futures_util::StreamExt;
-use image::EncodableLayout;
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
+use openssl::pkey::{PKey, Public};
+use openssl::sha::Sha1;
 use openssl::sign::Verifier;
 use serde::Serialize;
 use serde_json::json;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufReader, SeekFrom};
 use std::str::Utf8Error;
 use std::sync::Arc;
 use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 use crate::cdn::{Cdn, Connected};
+use crate::config::FirewallConfig;
+use crate::metrics::METRICS;
+use crate::processing::PoolError;
 use crate::rest::Resource;
+use crate::storage::{
+    crop::{CropRequest, InvalidCrop},
+    AnimationTooLarge, ItemLimitReached,
+};
 
-use super::GenericError;
+use super::{admin::signature_timestamp, blocking, GenericError};
 
 #[derive(Serialize)]
 pub struct UploadResponse {
@@ -32,6 +41,8 @@ pub struct UploadResponse {
 pub enum UploadError {
     #[error("Invalid public key")]
     InvalidPubKey(#[from] ErrorStack),
+    #[error("Public key could not be read")]
+    MissingPubKey(std::io::Error),
     #[error("Multipart error, {0}")]
     MultipartError(#[from] MultipartError),
     #[error("Could not decode utf8 data")]
@@ -40,8 +51,20 @@ pub enum UploadError {
     SerdeError(#[from] serde_json::Error),
     #[error("Missing {0} field in body")]
     MissingField(&'static str),
+    #[error("Missing {0} header")]
+    MissingHeader(&'static str),
+    #[error("{0}")]
+    BadRequest(String),
     #[error("Base64 could not be decoded")]
     Base64Error,
+    #[error("Image file size exceeds the limit of {0}MB")]
+    PayloadTooLarge(usize),
+    #[error("Unsupported image format for {0}")]
+    UnsupportedFormat(Resource),
+    #[error("Could not buffer upload")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    PoolError(#[from] PoolError),
     #[error("Internal server error")]
     InternalError,
     #[error("Unauthorized. {0}")]
@@ -57,6 +80,12 @@ impl ResponseError for UploadError {
             UploadError::MissingField(_) => HttpResponse::BadRequest().json(GenericError {
                 error: self.to_string(),
             }),
+            UploadError::MissingHeader(_) => HttpResponse::BadRequest().json(GenericError {
+                error: self.to_string(),
+            }),
+            UploadError::BadRequest(_) => HttpResponse::BadRequest().json(GenericError {
+                error: self.to_string(),
+            }),
             UploadError::InvalidPubKey(_) => {
                 HttpResponse::InternalServerError().body(self.to_string())
             }
@@ -68,60 +97,193 @@ impl ResponseError for UploadError {
             UploadError::Base64Error => HttpResponse::BadRequest().json(GenericError {
                 error: self.to_string(),
             }),
+            UploadError::PayloadTooLarge(_) => HttpResponse::PayloadTooLarge().json(GenericError {
+                error: self.to_string(),
+            }),
+            UploadError::UnsupportedFormat(_) => {
+                HttpResponse::UnsupportedMediaType().json(GenericError {
+                    error: self.to_string(),
+                })
+            }
             UploadError::Unauthorized(_) => HttpResponse::Unauthorized().json(GenericError {
                 error: self.to_string(),
             }),
+            UploadError::PoolError(ref err) => err.error_response(),
             _ => HttpResponse::InternalServerError().finish(),
         }
     }
 }
 
+const SIGNATURE_HEADER: &str = "X-Signature";
+const CROP_SIGNATURE_FIELD: &str = "crop_signature";
 const ONE_MB: usize = 1024 * 1024;
-const FILE_SIZE_LIMIT: usize = ONE_MB * 20;
+
+/// Number of bytes needed to tell the format of an image.
+const FORMAT_HEADER_SIZE: usize = 32;
+
+/// Longest value of a text field, such as a signature or a crop, in bytes.
+const MAX_TEXT_FIELD_SIZE: usize = 1024;
 
 pub async fn push_resource(
     path: web::Path<String>,
+    resource: web::Data<Resource>,
     mut payload: Multipart,
     data: web::Data<Arc<Cdn<Connected>>>,
     req: HttpRequest,
 ) -> Result<HttpResponse, UploadError> {
+    let resource = resource.get_ref().clone();
     let id = &path.as_str();
-    let firewall = &data.config.firewall;
-
-    if firewall.enabled {
-        let trusted_sources = &firewall.trusted_sources;
-
-        let peer_addr = req.peer_addr().unwrap().ip();
-        let real_ip_header_untrusted = req.headers().get("X-Real-IP");
-
-        let ip_addr = match real_ip_header_untrusted {
-            Some(real_ip_header) => {
-                // This means the request is coming from nginx, or it's in a development
-                // environment.
-                // In either case, it's secure to trust the header.
-                let peer_trusted = peer_addr.is_loopback();
-
-                if peer_trusted {
-                    real_ip_header
-                        .to_str()
-                        .map_err(|_| UploadError::InternalError)?
-                        .parse()
-                        .map_err(|_| UploadError::InternalError)?
-                } else {
-                    peer_addr
-                }
+
+    check_firewall(&req, &data.config.firewall, id)?;
+
+    let pkey = load_public_key()?;
+    let mut verifier = Verifier::new(MessageDigest::sha1(), &pkey)?;
+
+    let Upload {
+        image,
+        header,
+        size: image_size,
+        hash,
+        signature,
+        crop,
+        crop_signature,
+    } = read_upload(&mut payload, &mut verifier, resource.max_upload_size()).await?;
+
+    let decoded_signature = general_purpose::STANDARD
+        .decode(&signature)
+        .map_err(|_| UploadError::Base64Error)?;
+
+    METRICS.upload_bytes.observe(image_size as f64);
+
+    let verified =
+        tracing::info_span!("verify_signature").in_scope(|| verifier.verify(&decoded_signature))?;
+
+    if !verified {
+        METRICS
+            .signature_failures
+            .with_label_values(&["upload"])
+            .inc();
+        log::warn!("Got invalid signature: hash of uploaded image: {hash}, signature: {signature}");
+        return Err(UploadError::Unauthorized("Invalid signature"));
+    }
+
+    // The signature of the image doesn't cover the crop, which could otherwise be
+    // changed when replaying the upload
+    let crop = match crop {
+        Some((crop, field)) => {
+            let crop_signature =
+                crop_signature.ok_or(UploadError::MissingField(CROP_SIGNATURE_FIELD))?;
+            let decoded_signature = general_purpose::STANDARD
+                .decode(crop_signature)
+                .map_err(|_| UploadError::Base64Error)?;
+            let message = format!("crop:{resource}:{id}:{hash}:{field}");
+
+            if !verify_signature(message.as_bytes(), &decoded_signature)? {
+                METRICS
+                    .signature_failures
+                    .with_label_values(&["crop"])
+                    .inc();
+                log::warn!("Got invalid crop signature for {resource}/{id}: {field}");
+                return Err(UploadError::Unauthorized("Invalid crop signature"));
             }
-            None => peer_addr,
-        };
 
-        if !trusted_sources.contains(&ip_addr) {
-            log::warn!("Got request from unknown remote address: {ip_addr} (hash: {id})");
-            return Err(UploadError::Unauthorized("Unknown remote address"));
+            Some(crop)
         }
+        None => None,
+    };
+
+    match image::guess_format(&header) {
+        Ok(format) if resource.accepts(format) => (),
+        _ => return Err(UploadError::UnsupportedFormat(resource)),
     }
 
-    let mut image = Vec::new();
+    let storage = data.storage.clone();
+    let owned_id = id.to_string();
+    let owned_resource = resource.clone();
+
+    Ok(
+        match data
+            .pool
+            .run(move || {
+                storage.put(
+                    &owned_resource,
+                    &owned_id,
+                    BufReader::new(image),
+                    &hash,
+                    crop,
+                )
+            })
+            .await?
+        {
+            Ok(stored) => {
+                // Items of other resources are only replaced by uploading the same image
+                // again, which may still have been cropped differently
+                if resource.singleton() || stored.replaced {
+                    purge_cache(&data, &resource, id).await;
+                }
+
+                HttpResponse::Created().json(UploadResponse {
+                    filename: stored.filename,
+                })
+            }
+            Err(why) if why.is::<InvalidCrop>() => HttpResponse::BadRequest().json(GenericError {
+                error: why.to_string(),
+            }),
+            Err(why) if why.is::<AnimationTooLarge>() => {
+                HttpResponse::PayloadTooLarge().json(GenericError {
+                    error: why.to_string(),
+                })
+            }
+            Err(why) if why.is::<ItemLimitReached>() => {
+                HttpResponse::Conflict().json(GenericError {
+                    error: why.to_string(),
+                })
+            }
+            Err(why) => {
+                log::error!("Failed to store upload of {resource}/{id}: {why:#}");
+                HttpResponse::InternalServerError()
+                    .json(json!({ "error": "Internal server error", "message": why.to_string() }))
+            }
+        },
+    )
+}
+
+/// An uploaded image, buffered to a temporary file.
+struct Upload {
+    /// The image, rewound to its start
+    image: File,
+    /// The first bytes of the image, to tell its format by
+    header: Vec<u8>,
+    size: usize,
+    /// SHA1 hash of the image, hex encoded
+    hash: String,
+    /// Base64 encoded signature of the image
+    signature: String,
+    /// Crop sent along with the image, if any, and the field it was sent as, e.g.
+    /// `focal_point=120,80`
+    crop: Option<(CropRequest, String)>,
+    /// Base64 encoded signature of the crop
+    crop_signature: Option<String>,
+}
+
+/// Streams the multipart fields of an upload, hashing the image and feeding it to
+/// `verifier` on the way.
+#[tracing::instrument(name = "multipart", skip_all)]
+async fn read_upload(
+    payload: &mut Multipart,
+    verifier: &mut Verifier<'_>,
+    size_limit: usize,
+) -> Result<Upload, UploadError> {
+    let mut hasher = Sha1::new();
+
+    let image = web::block(tempfile::tempfile)
+        .await
+        .map_err(|_| UploadError::InternalError)??;
+    let mut image = tokio::fs::File::from_std(image);
+    let mut image_size = 0;
     let mut signature = String::new();
+    let mut crop = None;
+    let mut crop_signature: Option<String> = None;
 
     let (image_field, signature_field) = ("image", "signature");
 
@@ -129,38 +291,57 @@ pub async fn push_resource(
         let mut field = item?;
         let content_type = field.content_disposition();
 
-        let field_name = field.name();
-        dbg!(field_name);
-
-        match field_name {
+        match field.name() {
             name if name == image_field => {
                 if content_type.get_filename().is_none() {
-                    return Ok(HttpResponse::BadRequest().json(GenericError {
-                        error: "Image is not a file".to_string(),
-                    }));
+                    return Err(UploadError::BadRequest("Image is not a file".to_string()));
                 }
 
                 while let Some(chunk) = field.next().await {
                     let data = chunk?;
-                    image.extend(data);
+
+                    image_size += data.len();
+
+                    if image_size > size_limit {
+                        return Err(UploadError::PayloadTooLarge(size_limit / ONE_MB));
+                    }
+
+                    hasher.update(&data);
+                    verifier.update(&data)?;
+                    image.write_all(&data).await?;
                 }
             }
             name if name == signature_field => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
+                signature = read_text_field(&mut field).await?;
+            }
+            name if name == CROP_SIGNATURE_FIELD => {
+                crop_signature = Some(read_text_field(&mut field).await?);
+            }
+            name @ ("focal_point" | "crop") => {
+                let name = name.to_string();
+                let value = read_text_field(&mut field).await?;
+
+                let parsed = match name.as_str() {
+                    "focal_point" => CropRequest::focal_point(&value),
+                    _ => CropRequest::rectangle(&value),
+                }
+                .map_err(|why| UploadError::BadRequest(why.to_string()))?;
 
-                    signature.push_str(std::str::from_utf8(data.as_bytes())?);
+                if crop.replace((parsed, format!("{name}={value}"))).is_some() {
+                    return Err(UploadError::BadRequest(
+                        "Only one of focal_point and crop can be sent".to_string(),
+                    ));
                 }
             }
             field_name => {
-                return Ok(HttpResponse::BadRequest().json(GenericError {
-                    error: format!("Invalid payload field \"{field_name}\""),
-                }));
+                return Err(UploadError::BadRequest(format!(
+                    "Invalid payload field \"{field_name}\""
+                )));
             }
         }
     }
 
-    if image.is_empty() {
+    if image_size == 0 {
         return Err(UploadError::MissingField(image_field));
     }
 
@@ -168,44 +349,236 @@ pub async fn push_resource(
         return Err(UploadError::MissingField(signature_field));
     }
 
-    if image.len() > FILE_SIZE_LIMIT {
-        let limit_mb = FILE_SIZE_LIMIT / ONE_MB;
+    let mut header = Vec::with_capacity(FORMAT_HEADER_SIZE);
+    image.flush().await?;
+    image.seek(SeekFrom::Start(0)).await?;
+    (&mut image)
+        .take(FORMAT_HEADER_SIZE as u64)
+        .read_to_end(&mut header)
+        .await?;
+    image.seek(SeekFrom::Start(0)).await?;
 
-        return Ok(HttpResponse::BadRequest().json(GenericError {
-            error: format!("Image file size exceeds the limit of {limit_mb}MB"),
-        }));
+    Ok(Upload {
+        image: image.into_std().await,
+        header,
+        size: image_size,
+        hash: hex::encode(hasher.finish()),
+        signature,
+        crop,
+        crop_signature,
+    })
+}
+
+/// Reads the value of a text field, which may be at most [`MAX_TEXT_FIELD_SIZE`] bytes
+/// long, so that the value isn't buffered whatever its size.
+async fn read_text_field(field: &mut Field) -> Result<String, UploadError> {
+    let mut value = Vec::new();
+
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+
+        if value.len() + chunk.len() > MAX_TEXT_FIELD_SIZE {
+            return Err(UploadError::BadRequest(format!(
+                "The field \"{}\" is longer than {MAX_TEXT_FIELD_SIZE} bytes",
+                field.name()
+            )));
+        }
+
+        value.extend_from_slice(&chunk);
     }
 
-    let digest = openssl::sha::sha1(&image);
-    let hash = hex::encode(digest);
+    Ok(String::from_utf8(value).map_err(|why| why.utf8_error())?)
+}
 
-    let decoded_signature = general_purpose::STANDARD
-        .decode(&signature)
-        .map_err(|_| UploadError::Base64Error)?;
+/// Restores a previous version of a singleton resource.
+///
+/// The `X-Signature` header has to carry a signature of
+/// `rollback:{resource}:{id}:{hash}:{timestamp}`, made at most five minutes before or
+/// after the `X-Signature-Timestamp` it is sent with.
+pub async fn rollback_resource(
+    path: web::Path<(String, String)>,
+    resource: web::Data<Resource>,
+    data: web::Data<Arc<Cdn<Connected>>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, UploadError> {
+    let (id, hash) = path.into_inner();
+    let resource = resource.get_ref().clone();
 
-    if !verify_signature(&image, &decoded_signature)? {
-        log::warn!("Got invalid signature: hash of uploaded image: {hash}, signature: {signature}");
+    check_firewall(&req, &data.config.firewall, &id)?;
+
+    let timestamp = signature_timestamp(&req)?;
+    let message = format!("rollback:{resource}:{id}:{hash}:{timestamp}");
+
+    if !verify_signature_header(&req, &message)? {
+        METRICS
+            .signature_failures
+            .with_label_values(&["rollback"])
+            .inc();
+        log::warn!("Got invalid signature for rollback of {resource}/{id} to {hash}");
         return Err(UploadError::Unauthorized("Invalid signature"));
     }
 
+    let storage = data.storage.clone();
+    let owned_resource = resource.clone();
+    let owned_id = id.clone();
+
     Ok(
-        match data.storage.put(Resource::Avatars, id, image, &hash) {
-            Ok(filename) => HttpResponse::Created().json(UploadResponse { filename }),
-            Err(why) => {
-                dbg!(&why);
-                HttpResponse::InternalServerError()
-                    .json(json!({ "error": "Internal server error", "message": why.to_string() }))
+        match blocking(move || storage.rollback(&owned_resource, &owned_id, &hash)).await {
+            Ok(Some(filename)) => {
+                purge_cache(&data, &resource, &id).await;
+                HttpResponse::Ok().json(UploadResponse { filename })
+            }
+            Ok(None) => HttpResponse::NotFound().json(GenericError {
+                error: "Version not found".to_string(),
+            }),
+            Err(why) => HttpResponse::InternalServerError()
+                .json(json!({ "error": "Internal server error", "message": why.to_string() })),
+        },
+    )
+}
+
+/// Removes an item of a non-singleton resource.
+///
+/// The `X-Signature` header has to carry a signature of
+/// `delete:{resource}:{id}:{hash}:{timestamp}`, made at most five minutes before or
+/// after the `X-Signature-Timestamp` it is sent with.
+pub async fn delete_resource(
+    path: web::Path<(String, String)>,
+    resource: web::Data<Resource>,
+    data: web::Data<Arc<Cdn<Connected>>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, UploadError> {
+    let (id, hash) = path.into_inner();
+    let resource = resource.get_ref().clone();
+
+    check_firewall(&req, &data.config.firewall, &id)?;
+
+    let timestamp = signature_timestamp(&req)?;
+    let message = format!("delete:{resource}:{id}:{hash}:{timestamp}");
+
+    if !verify_signature_header(&req, &message)? {
+        METRICS
+            .signature_failures
+            .with_label_values(&["delete"])
+            .inc();
+        log::warn!("Got invalid signature for deletion of {resource}/{id}/{hash}");
+        return Err(UploadError::Unauthorized("Invalid signature"));
+    }
+
+    if resource.singleton() {
+        return Ok(HttpResponse::MethodNotAllowed().json(GenericError {
+            error: format!("Items of {resource} are replaced by uploading, not deleted"),
+        }));
+    }
+
+    let storage = data.storage.clone();
+    let owned_resource = resource.clone();
+    let owned_id = id.clone();
+
+    Ok(
+        match data
+            .pool
+            .run(move || storage.delete(&owned_resource, &owned_id, &hash))
+            .await?
+        {
+            Ok(true) => {
+                purge_cache(&data, &resource, &id).await;
+                HttpResponse::NoContent().finish()
             }
+            Ok(false) => HttpResponse::NotFound().json(GenericError {
+                error: "Item not found".to_string(),
+            }),
+            Err(why) => HttpResponse::InternalServerError()
+                .json(json!({ "error": "Internal server error", "message": why.to_string() })),
         },
     )
 }
 
-fn verify_signature(data: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+/// Drops the cached renditions of a resource whose current version changed.
+///
+/// A failure is only logged: the stored version is already committed, and stale
+/// entries expire on their own.
+async fn purge_cache(data: &Cdn<Connected>, resource: &Resource, id: &str) {
+    if let Err(why) = data.cache.purge(resource, id).await {
+        log::warn!("Failed to purge cached renditions of {resource}/{id}: {why}");
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub(super) fn check_firewall(
+    req: &HttpRequest,
+    firewall: &FirewallConfig,
+    id: &str,
+) -> Result<(), UploadError> {
+    if !firewall.enabled {
+        return Ok(());
+    }
+
+    let trusted_sources = &firewall.trusted_sources;
+
+    let peer_addr = req.peer_addr().unwrap().ip();
+    let real_ip_header_untrusted = req.headers().get("X-Real-IP");
+
+    let ip_addr = match real_ip_header_untrusted {
+        Some(real_ip_header) => {
+            // This means the request is coming from nginx, or it's in a development
+            // environment.
+            // In either case, it's secure to trust the header.
+            let peer_trusted = peer_addr.is_loopback();
+
+            if peer_trusted {
+                real_ip_header
+                    .to_str()
+                    .map_err(|_| UploadError::InternalError)?
+                    .parse()
+                    .map_err(|_| UploadError::InternalError)?
+            } else {
+                peer_addr
+            }
+        }
+        None => peer_addr,
+    };
+
+    if !trusted_sources.contains(&ip_addr) {
+        METRICS.firewall_rejections.inc();
+        log::warn!("Got request from unknown remote address: {ip_addr} (hash: {id})");
+        return Err(UploadError::Unauthorized("Unknown remote address"));
+    }
+
+    Ok(())
+}
+
+pub(super) fn load_public_key() -> Result<PKey<Public>, UploadError> {
     let pkey_path = std::env::var("PUBLIC_KEY_PATH").unwrap_or("./certs/staging.pub".to_string());
-    let pkey = fs::read_to_string(pkey_path).expect("Unable to load public key");
-    let pkey = PKey::public_key_from_pem(pkey.as_bytes())?;
+    let pkey = fs::read_to_string(pkey_path).map_err(UploadError::MissingPubKey)?;
+
+    Ok(PKey::public_key_from_pem(pkey.as_bytes())?)
+}
+
+/// Checks that the `X-Signature` header carries a valid signature of `message`.
+pub(super) fn verify_signature_header(
+    req: &HttpRequest,
+    message: &str,
+) -> Result<bool, UploadError> {
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .ok_or(UploadError::MissingHeader(SIGNATURE_HEADER))?
+        .to_str()
+        .map_err(|_| UploadError::Base64Error)?;
+
+    let decoded_signature = general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|_| UploadError::Base64Error)?;
+
+    verify_signature(message.as_bytes(), &decoded_signature)
+}
+
+#[tracing::instrument(skip_all)]
+fn verify_signature(data: &[u8], signature: &[u8]) -> Result<bool, UploadError> {
+    let pkey = load_public_key()?;
     let mut verifier = Verifier::new(MessageDigest::sha1(), &pkey)?;
 
     verifier.update(data)?;
-    verifier.verify(signature)
+    Ok(verifier.verify(signature)?)
 }
@@ -0,0 +1,346 @@
+use std::str::FromStr;
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView, RgbaImage};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::{AspectRatio, CropMode};
+
+/// Length in pixels of the shorter side of the region that entropy is measured on.
+const ENTROPY_SAMPLE_SIZE: u32 = 64;
+
+/// Length in pixels of the longer side of the thumbnail entropy is measured on, at most.
+const MAX_ENTROPY_SAMPLE_LENGTH: u32 = 1024;
+
+/// Number of positions of the region entropy is measured at, at most.
+const MAX_ENTROPY_POSITIONS: u32 = 256;
+
+/// How many times longer one side of an upload may be than the other.
+const MAX_IMAGE_RATIO: u64 = 32;
+
+/// Pixels of the canvas an image is padded to with `contain`, at most.
+const MAX_CANVAS_PIXELS: u64 = 8192 * 8192;
+
+/// Returned when an upload can't be cropped, e.g. because the crop sent with it
+/// doesn't fit the image.
+#[derive(Debug, Error)]
+#[error("Invalid crop: {0}")]
+pub struct InvalidCrop(pub String);
+
+/// A crop sent along with an upload, overriding the crop mode of its resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CropRequest {
+    /// Keep the largest region centered on this point, as close as the image allows
+    FocalPoint { x: u32, y: u32 },
+    /// Keep exactly this region, which has to have the aspect ratio of the resource
+    Rectangle(Rect),
+}
+
+impl CropRequest {
+    /// Parses a focal point sent as `x,y`.
+    pub fn focal_point(value: &str) -> Result<Self, InvalidCrop> {
+        match parse_numbers(value)?[..] {
+            [x, y] => Ok(Self::FocalPoint { x, y }),
+            _ => Err(InvalidCrop(format!("Expected x,y but got \"{value}\""))),
+        }
+    }
+
+    /// Parses a rectangle sent as `x,y,width,height`.
+    pub fn rectangle(value: &str) -> Result<Self, InvalidCrop> {
+        match parse_numbers(value)?[..] {
+            [x, y, width, height] => Ok(Self::Rectangle(Rect {
+                x,
+                y,
+                width,
+                height,
+            })),
+            _ => Err(InvalidCrop(format!(
+                "Expected x,y,width,height but got \"{value}\""
+            ))),
+        }
+    }
+}
+
+fn parse_numbers(value: &str) -> Result<Vec<u32>, InvalidCrop> {
+    value
+        .split(',')
+        .map(|number| {
+            u32::from_str(number.trim())
+                .map_err(|_| InvalidCrop(format!("\"{number}\" is not a valid coordinate")))
+        })
+        .collect()
+}
+
+/// A region of an image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CropKind {
+    Center,
+    Entropy,
+    FocalPoint,
+    Rectangle,
+    Contain,
+}
+
+/// The crop applied to an upload, which is all that's needed to crop the original
+/// the same way again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crop {
+    /// How the region was chosen
+    pub kind: CropKind,
+    /// The region of the original image that was kept. With `contain`, this is the
+    /// whole image, which is padded instead.
+    #[serde(flatten)]
+    pub region: Rect,
+}
+
+impl Crop {
+    /// Decides how an image is cropped to `ratio`, by the `requested` crop if one was
+    /// sent with the upload, and by `mode` otherwise.
+    ///
+    /// `image` is only looked at for entropy based crops.
+    pub fn resolve(
+        image: &DynamicImage,
+        mode: CropMode,
+        ratio: AspectRatio,
+        requested: Option<CropRequest>,
+    ) -> Result<Self, InvalidCrop> {
+        let (width, height) = image.dimensions();
+        let (long_side, short_side) = (width.max(height) as u64, width.min(height) as u64);
+
+        if long_side > short_side * MAX_IMAGE_RATIO {
+            return Err(InvalidCrop(format!(
+                "The image of {width}x{height} is more than {MAX_IMAGE_RATIO} times as long as it is wide"
+            )));
+        }
+
+        let (region_width, region_height) = largest_region(width, height, ratio);
+
+        let (kind, region) = match (requested, mode) {
+            (Some(CropRequest::Rectangle(rect)), _) => {
+                if rect.width == 0 || rect.height == 0 || !has_ratio(rect, ratio) {
+                    return Err(InvalidCrop(format!(
+                        "The rectangle has to have an aspect ratio of {ratio}"
+                    )));
+                }
+
+                if rect.x.saturating_add(rect.width) > width
+                    || rect.y.saturating_add(rect.height) > height
+                {
+                    return Err(InvalidCrop(format!(
+                        "The rectangle exceeds the image of {width}x{height}"
+                    )));
+                }
+
+                (CropKind::Rectangle, rect)
+            }
+            (Some(CropRequest::FocalPoint { x, y }), _) => {
+                if x >= width || y >= height {
+                    return Err(InvalidCrop(format!(
+                        "The focal point lies outside the image of {width}x{height}"
+                    )));
+                }
+
+                let region = Rect {
+                    x: centered_offset(x, region_width, width),
+                    y: centered_offset(y, region_height, height),
+                    width: region_width,
+                    height: region_height,
+                };
+
+                (CropKind::FocalPoint, region)
+            }
+            (None, CropMode::Center) => {
+                let region = Rect {
+                    x: (width - region_width) / 2,
+                    y: (height - region_height) / 2,
+                    width: region_width,
+                    height: region_height,
+                };
+
+                (CropKind::Center, region)
+            }
+            (None, CropMode::Entropy) => {
+                let (x, y) = entropy_offset(image, region_width, region_height);
+
+                let region = Rect {
+                    x,
+                    y,
+                    width: region_width,
+                    height: region_height,
+                };
+
+                (CropKind::Entropy, region)
+            }
+            (None, CropMode::Contain) => {
+                let (canvas_width, canvas_height) = smallest_canvas(width, height, ratio);
+
+                if canvas_width as u64 * canvas_height as u64 > MAX_CANVAS_PIXELS {
+                    return Err(InvalidCrop(format!(
+                        "The image of {width}x{height} can't be padded to an aspect ratio of {ratio}"
+                    )));
+                }
+
+                let region = Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                };
+
+                (CropKind::Contain, region)
+            }
+        };
+
+        Ok(Self { kind, region })
+    }
+
+    /// Crops `image`, which has to have the dimensions of the image the crop was
+    /// resolved for.
+    ///
+    /// With `contain`, `ratio` has to be the one the crop was resolved for.
+    pub fn apply(&self, image: &DynamicImage, ratio: AspectRatio) -> DynamicImage {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = self.region;
+
+        match self.kind {
+            CropKind::Contain => {
+                let (canvas_width, canvas_height) = smallest_canvas(width, height, ratio);
+                let mut canvas = RgbaImage::new(canvas_width, canvas_height);
+
+                image::imageops::overlay(
+                    &mut canvas,
+                    &image.to_rgba8(),
+                    ((canvas_width - width) / 2).into(),
+                    ((canvas_height - height) / 2).into(),
+                );
+
+                DynamicImage::ImageRgba8(canvas)
+            }
+            _ => image.crop_imm(x, y, width, height),
+        }
+    }
+}
+
+/// Dimensions of the largest region of `ratio` that fits an image.
+fn largest_region(width: u32, height: u32, ratio: AspectRatio) -> (u32, u32) {
+    let fitted_width = ratio.width_for(height);
+
+    match fitted_width <= width {
+        true => (fitted_width, height),
+        false => (width, ratio.height_for(width).min(height)),
+    }
+}
+
+/// Dimensions of the smallest canvas of `ratio` an image fits in.
+fn smallest_canvas(width: u32, height: u32, ratio: AspectRatio) -> (u32, u32) {
+    let fitted_width = ratio.width_for(height);
+
+    match fitted_width >= width {
+        true => (fitted_width, height),
+        false => (width, ratio.height_for(width).max(height)),
+    }
+}
+
+/// Whether `rect` has `ratio`, give or take the rounding to whole pixels.
+fn has_ratio(rect: Rect, ratio: AspectRatio) -> bool {
+    ratio.height_for(rect.width).abs_diff(rect.height) <= 1
+        || ratio.width_for(rect.height).abs_diff(rect.width) <= 1
+}
+
+/// Offset of a window of `window` pixels centered on `point`, kept within `length`.
+fn centered_offset(point: u32, window: u32, length: u32) -> u32 {
+    point.saturating_sub(window / 2).min(length - window)
+}
+
+/// Finds the region of `region_width` by `region_height` pixels with the most detail,
+/// by sliding it along the axis it can move on over a thumbnail, and measuring the
+/// entropy of its brightness.
+///
+/// The thumbnail and the number of positions are bounded, so that long strips don't
+/// take long to measure.
+fn entropy_offset(image: &DynamicImage, region_width: u32, region_height: u32) -> (u32, u32) {
+    let (width, height) = image.dimensions();
+    let horizontal = region_width < width;
+
+    if region_width == width && region_height == height {
+        return (0, 0);
+    }
+
+    let scale = (ENTROPY_SAMPLE_SIZE as f64 / region_width.min(region_height) as f64)
+        .min(MAX_ENTROPY_SAMPLE_LENGTH as f64 / width.max(height) as f64);
+    let scaled = |length: u32| ((length as f64 * scale).round() as u32).max(1);
+
+    let sample = image
+        .resize_exact(scaled(width), scaled(height), FilterType::Triangle)
+        .to_luma8();
+    let (sample_width, sample_height) = sample.dimensions();
+    let window_width = scaled(region_width).min(sample_width);
+    let window_height = scaled(region_height).min(sample_height);
+
+    let positions = match horizontal {
+        true => sample_width - window_width,
+        false => sample_height - window_height,
+    };
+
+    // Long strips are sampled at evenly spaced positions
+    let step = positions.div_ceil(MAX_ENTROPY_POSITIONS).max(1);
+
+    let best = (0..=positions)
+        .step_by(step as usize)
+        .map(|position| {
+            let (x, y) = match horizontal {
+                true => (position, 0),
+                false => (0, position),
+            };
+
+            let mut histogram = [0u32; 256];
+
+            for dy in 0..window_height {
+                for dx in 0..window_width {
+                    histogram[sample.get_pixel(x + dx, y + dy).0[0] as usize] += 1;
+                }
+            }
+
+            (position, entropy(&histogram))
+        })
+        .fold((0, f64::MIN), |best, candidate| {
+            match candidate.1 > best.1 {
+                true => candidate,
+                false => best,
+            }
+        })
+        .0;
+
+    let offset = (best as f64 / scale).round() as u32;
+
+    match horizontal {
+        true => (offset.min(width - region_width), 0),
+        false => (0, offset.min(height - region_height)),
+    }
+}
+
+fn entropy(histogram: &[u32]) -> f64 {
+    let total: u32 = histogram.iter().sum();
+
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let probability = *count as f64 / total as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
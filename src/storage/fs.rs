@@ -0,0 +1,209 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use fs2::FileExt;
+
+use super::{lock::BackendLock, StorageBackend};
+
+const STAGING_EXTENSION: &str = "staging";
+
+/// Age after which a staging file is abandoned, even if the process that wrote it still
+/// runs. Writes take seconds at most, so this only keeps slow ones from being cut short.
+const STAGING_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Directory beneath `storage_path` holding the lock files of ids.
+const LOCKS_DIR: &str = ".locks";
+
+/// Stores resources on the local file system, under `storage_path`.
+///
+/// Files are first written to a staging file next to their destination and then
+/// renamed into place, so a file is never observed half-written.
+pub struct FilesystemBackend {
+    storage_path: PathBuf,
+    staging_counter: AtomicUsize,
+}
+
+impl FilesystemBackend {
+    pub fn new(storage_path: &str) -> Self {
+        Self {
+            storage_path: PathBuf::from(storage_path),
+            staging_counter: AtomicUsize::new(0),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.storage_path.join(key)
+    }
+
+    fn staging_path(&self, path: &Path) -> PathBuf {
+        let count = self.staging_counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut staging_path = path.as_os_str().to_owned();
+        staging_path.push(format!(".{}-{count}.{STAGING_EXTENSION}", process::id()));
+
+        PathBuf::from(staging_path)
+    }
+}
+
+fn is_staging(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == STAGING_EXTENSION)
+}
+
+/// The id of the process that wrote a staging file, from its name.
+fn staging_pid(path: &Path) -> Option<u32> {
+    let stem = path.file_stem()?.to_str()?;
+    let (_, suffix) = stem.rsplit_once('.')?;
+    let (pid, _) = suffix.split_once('-')?;
+
+    pid.parse().ok()
+}
+
+/// Whether a process with this id runs, as far as can be told. Processes of other
+/// hosts sharing `storage_path` can't be, and are left to [`STAGING_MAX_AGE`].
+fn is_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+/// Whether a staging file was left behind by a write that will never complete.
+///
+/// One that was renamed into place in the meantime is not.
+fn is_abandoned(path: &Path) -> Result<bool> {
+    let metadata = match fs::metadata(path) {
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(false),
+        metadata => metadata?,
+    };
+    let age = metadata.modified()?.elapsed().unwrap_or_default();
+
+    Ok(age > STAGING_MAX_AGE || staging_pid(path).is_some_and(|pid| !is_running(pid)))
+}
+
+fn remove_staging_files(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            removed += remove_staging_files(&path)?;
+        } else if is_staging(&path) && is_abandoned(&path)? {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+impl StorageBackend for FilesystemBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+
+        match path.try_exists() {
+            Ok(true) => Ok(Some(fs::read(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let staging_path = self.staging_path(&path);
+
+        let result = fs::File::create(&staging_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&staging_path, &path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&staging_path);
+        }
+
+        Ok(result?)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(why) if why.kind() != io::ErrorKind::NotFound => Err(why.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let path = self.path(prefix);
+
+        if !path.try_exists()? {
+            return Ok(Vec::new());
+        }
+
+        let mut keys = Vec::new();
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.is_file() && !is_staging(&path) {
+                keys.push(format!("{prefix}/{}", entry.file_name().to_string_lossy()));
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key).try_exists()?)
+    }
+
+    /// Takes an exclusive lock on a file per key, which also keeps out other
+    /// processes sharing `storage_path`.
+    fn lock(&self, key: &str) -> Result<BackendLock> {
+        let path = self.storage_path.join(LOCKS_DIR).join(key);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock_exclusive()?;
+
+        Ok(BackendLock::new(move || {
+            let _ = file.unlock();
+        }))
+    }
+
+    fn recover(&self) -> Result<usize> {
+        if !self.storage_path.try_exists()? {
+            return Ok(0);
+        }
+
+        remove_staging_files(&self.storage_path)
+    }
+
+    fn available_space(&self) -> Result<Option<u64>> {
+        fs::create_dir_all(&self.storage_path)?;
+
+        Ok(Some(fs2::available_space(&self.storage_path)?))
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+
+/// Serializes writes to the same id within this instance, so that read-modify-write
+/// of its manifest doesn't lose concurrent updates.
+///
+/// Other instances are kept out by the lock of the backend, see
+/// [`StorageBackend::lock`](super::StorageBackend::lock).
+#[derive(Default)]
+pub struct IdLocks {
+    held: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl IdLocks {
+    /// Blocks until no other thread holds the lock on `key`, and takes it.
+    pub fn lock(&self, key: &str) -> IdLock<'_> {
+        let mut held = self.held.lock().unwrap_or_else(|err| err.into_inner());
+
+        while held.contains(key) {
+            held = self
+                .released
+                .wait(held)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+
+        held.insert(key.to_string());
+
+        IdLock {
+            locks: self,
+            key: key.to_string(),
+        }
+    }
+}
+
+/// Releases the lock on an id when dropped.
+pub struct IdLock<'a> {
+    locks: &'a IdLocks,
+    key: String,
+}
+
+impl Drop for IdLock<'_> {
+    fn drop(&mut self) {
+        let mut held = self
+            .locks
+            .held
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        held.remove(&self.key);
+        self.locks.released.notify_all();
+    }
+}
+
+/// A lock taken in a storage backend, released when dropped.
+///
+/// Backends that only one instance writes to hand out locks that do nothing.
+#[derive(Default)]
+pub struct BackendLock {
+    release: Option<Box<dyn FnOnce() + Send>>,
+    /// Set by the backend once the lock was taken over by another instance
+    lost: Arc<AtomicBool>,
+}
+
+impl BackendLock {
+    pub fn new(release: impl FnOnce() + Send + 'static) -> Self {
+        Self {
+            release: Some(Box::new(release)),
+            lost: Arc::default(),
+        }
+    }
+
+    /// Has the lock report itself as lost once `lost` is set.
+    pub fn with_lost(mut self, lost: Arc<AtomicBool>) -> Self {
+        self.lost = lost;
+        self
+    }
+
+    /// Fails if the lock was lost, so that a writer can stop before it overwrites
+    /// what another instance wrote in the meantime.
+    pub fn ensure_held(&self) -> Result<()> {
+        if self.lost.load(Ordering::Acquire) {
+            bail!("The lock was lost to another instance");
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BackendLock {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
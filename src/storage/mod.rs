@@ -0,0 +1,801 @@
+pub mod crop;
+pub mod fs;
+pub mod lock;
+pub mod s3;
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::{codecs::gif::GifDecoder, io::Reader, DynamicImage, ImageOutputFormat::Png};
+use image::{
+    AnimationDecoder, Frame, Frames, GenericImageView, ImageDecoder, ImageEncoder, ImageFormat,
+    RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Cursor, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::config::{CdnConfig, StorageBackendKind};
+use crate::index::{MetadataIndex, ResourceMetadata};
+use crate::metrics::METRICS;
+use crate::rest::Resource;
+
+use self::crop::{Crop, CropRequest};
+use self::fs::FilesystemBackend;
+use self::lock::{BackendLock, IdLock, IdLocks};
+use self::s3::S3Backend;
+
+/// Whether an image holds an animation rather than a still, rewinding it afterwards.
+///
+/// GIFs count as animations even with a single frame.
+fn is_animated<R: BufRead + Seek>(format: ImageFormat, image_data: &mut R) -> Result<bool> {
+    let animated = match format {
+        ImageFormat::Gif => true,
+        ImageFormat::Png => PngDecoder::new(&mut *image_data)?.is_apng(),
+        ImageFormat::WebP => WebPDecoder::new(&mut *image_data)?.has_animation(),
+        _ => false,
+    };
+
+    image_data.rewind()?;
+
+    Ok(animated)
+}
+
+/// Most frames an animation may have.
+pub const MAX_FRAME_COUNT: u32 = 1000;
+
+/// Most pixels the frames of an animation may have in total, once cropped or resized.
+pub const MAX_ANIMATION_PIXELS: u64 = 128 * 1024 * 1024;
+
+/// Returned by [`Storage::put`] when an animation has more frames or pixels than
+/// [`MAX_FRAME_COUNT`] and [`MAX_ANIMATION_PIXELS`] allow.
+#[derive(Debug, Error)]
+#[error(
+    "An animation can have at most {MAX_FRAME_COUNT} frames of {MAX_ANIMATION_PIXELS} pixels in total"
+)]
+pub struct AnimationTooLarge;
+
+/// Decodes the frames of an animated GIF, PNG or WebP one at a time, along with its
+/// dimensions.
+pub fn decode_frames<'a, R: BufRead + Seek + 'a>(
+    format: ImageFormat,
+    image_data: R,
+) -> Result<((u32, u32), Frames<'a>)> {
+    let decoded = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(image_data)?;
+            (decoder.dimensions(), decoder.into_frames())
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(image_data)?;
+            (decoder.dimensions(), decoder.apng().into_frames())
+        }
+        // Animations whose frames are all alike are encoded as still WebPs
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(image_data)?;
+            let dimensions = decoder.dimensions();
+
+            match decoder.has_animation() {
+                true => (dimensions, decoder.into_frames()),
+                false => {
+                    let image = DynamicImage::from_decoder(decoder)?;
+                    let frame = Frame::new(image.to_rgba8());
+                    (
+                        dimensions,
+                        Frames::new(Box::new(std::iter::once(Ok(frame)))),
+                    )
+                }
+            }
+        }
+        _ => return Err(anyhow!("{format:?} images can't be animated")),
+    };
+
+    Ok(decoded)
+}
+
+/// Encodes frames of `width` by `height` pixels as an animated WebP that loops forever.
+pub fn encode_animated_webp(
+    frames: &[Frame],
+    width: u32,
+    height: u32,
+    config: &webp::WebPConfig,
+) -> Result<Vec<u8>> {
+    // The encoder can't report this error without crashing
+    if frames.is_empty() {
+        return Err(anyhow!("Animation has no frames"));
+    }
+
+    let mut encoder = webp::AnimEncoder::new(width, height, config);
+    let mut timestamp = 0;
+
+    for frame in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.buffer().as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        timestamp += (numerator / denominator.max(1)) as i32;
+    }
+
+    let encoded = encoder
+        .try_encode()
+        .map_err(|why| anyhow!("Failed to encode animated WebP: {why:?}"))?;
+
+    let mut encoded = encoded.to_vec();
+    set_last_frame_duration(&mut encoded, timestamp as u32)?;
+
+    Ok(encoded)
+}
+
+/// Lets the last frame of an animated WebP last until `end`, in milliseconds after the
+/// start of the animation.
+///
+/// The encoder ends animations at a timestamp of 0, and so makes the last frame last as
+/// long as the average frame instead. Frames it merged are covered, as the last frame
+/// starts where all frames before it end.
+fn set_last_frame_duration(webp: &mut [u8], end: u32) -> Result<()> {
+    // Skips the RIFF header, and reads the duration of every ANMF chunk
+    let mut offset = 12;
+    let mut start = 0;
+    let mut last = None;
+
+    while let Some(header) = webp.get(offset..offset + 8) {
+        let size = u32::from_le_bytes(header[4..].try_into()?) as usize;
+
+        if &header[..4] == b"ANMF" {
+            // The 24 bit duration follows the position and dimensions of the frame
+            let duration = offset + 8 + 12;
+
+            if webp.len() < duration + 3 {
+                bail!("Truncated frame in animated WebP");
+            }
+
+            if let Some(previous) = last.replace(duration) {
+                let bytes = &webp[previous..previous + 3];
+                start += u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+            }
+        }
+
+        offset += 8 + size + size % 2;
+    }
+
+    let Some(last) = last else {
+        bail!("Animated WebP has no frames");
+    };
+
+    // Delays of 0 are left to the encoder
+    if end > start {
+        let duration = (end - start).min(0xFF_FFFF);
+        webp[last..last + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+
+    Ok(())
+}
+
+/// A place where processed resources are kept.
+///
+/// Keys are `/` separated paths relative to the root of the backend,
+/// e.g. `avatars/1234567890/{hash}.png`.
+pub trait StorageBackend: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    /// Removes the object at `key`. Removing one that doesn't exist succeeds.
+    fn delete(&self, key: &str) -> Result<()>;
+    /// Returns the keys of all objects directly beneath `prefix`.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Cleans up after writes that were interrupted, returning the number of
+    /// leftover objects that were removed.
+    ///
+    /// Backends whose writes are atomic by nature have nothing to recover.
+    fn recover(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Free space left for new objects in bytes, if the backend is bounded.
+    fn available_space(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Blocks until no other instance writes to `key`, and keeps them out until the
+    /// returned lock is dropped.
+    ///
+    /// Backends that aren't shared between instances need no lock.
+    fn lock(&self, _key: &str) -> Result<BackendLock> {
+        Ok(BackendLock::default())
+    }
+}
+
+/// Lists the versions kept for a singleton resource, next to its files.
+const MANIFEST_FILENAME: &str = "versions.json";
+
+/// Directory next to the files of an id, holding the metadata of each of its items
+/// as `{hash}.json`.
+///
+/// Items are listed, counted and removed through it rather than through the index, as
+/// the index is local to an instance, while the backend may be shared by several. Each
+/// item is an object of its own, so storing one doesn't rewrite all others.
+const ITEMS_DIR: &str = "items";
+
+/// Prefix of the objects written and removed again to check whether the backend
+/// accepts writes.
+const PROBE_PREFIX: &str = ".probe";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    /// SHA1 hash of the uploaded file
+    pub hash: String,
+    /// The file the version is served under
+    pub filename: String,
+    /// Format of the uploaded file
+    pub format: String,
+    /// Upload time in seconds since the unix epoch
+    pub uploaded_at: u64,
+    /// All files that belong to the version
+    pub files: Vec<String>,
+}
+
+/// What [`Storage::put`] stored.
+#[derive(Debug)]
+pub struct Stored {
+    /// Name of the file the upload is served as
+    pub filename: String,
+    /// Whether an item with the same hash was overwritten, e.g. with another crop, so
+    /// anything rendered from it before is stale
+    pub replaced: bool,
+}
+
+/// Returned by [`Storage::put`] when an id already holds as many items as its
+/// resource allows.
+#[derive(Debug, Error)]
+#[error("An id can hold at most {0} items")]
+pub struct ItemLimitReached(pub usize);
+
+fn item_key(base_path: &str, hash: &str) -> String {
+    format!("{base_path}/{ITEMS_DIR}/{hash}.json")
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    index: MetadataIndex,
+    /// Number of previous versions kept for singleton resources
+    history: usize,
+    locks: Arc<IdLocks>,
+}
+
+impl Storage {
+    pub fn new(backend: Arc<dyn StorageBackend>, index: MetadataIndex, history: usize) -> Self {
+        Self {
+            backend,
+            index,
+            history,
+            locks: Arc::default(),
+        }
+    }
+
+    pub fn from_config(config: &CdnConfig) -> Result<Self> {
+        let backend: Arc<dyn StorageBackend> = match config.storage.backend {
+            StorageBackendKind::Filesystem => {
+                let storage_path = config.storage_path.as_deref().unwrap_or("./uploads");
+
+                Arc::new(FilesystemBackend::new(storage_path))
+            }
+            StorageBackendKind::S3 => {
+                let s3_config = config
+                    .storage
+                    .s3
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Missing [storage.s3] section"))?;
+
+                Arc::new(S3Backend::new(s3_config)?)
+            }
+        };
+
+        let index = MetadataIndex::open(&config.storage.index_path)?;
+
+        Ok(Self::new(backend, index, config.storage.history))
+    }
+
+    fn path(&self, resource: &Resource, id: &str) -> String {
+        format!("{resource}/{id}")
+    }
+
+    /// Locks the files of an id against concurrent writes, from this instance and others.
+    fn lock(&self, base_path: &str) -> Result<(IdLock<'_>, BackendLock)> {
+        let id_lock = self.locks.lock(base_path);
+        let backend_lock = self.backend.lock(base_path)?;
+
+        Ok((id_lock, backend_lock))
+    }
+
+    #[tracing::instrument(name = "storage_read", skip(self))]
+    pub fn get(&self, resource: &Resource, id: &str, filename: &str) -> Result<Option<Vec<u8>>> {
+        let key = format!("{}/{filename}", self.path(resource, id));
+
+        self.backend.get(&key)
+    }
+
+    pub fn put<R: BufRead + Seek>(
+        &self,
+        resource: &Resource,
+        id: &str,
+        mut image_data: R,
+        hash: &str,
+        requested_crop: Option<CropRequest>,
+    ) -> Result<Stored> {
+        // Checked again when committing, this only spares decoding uploads that can't be
+        // stored anyway
+        if let Some(max_items) = resource.max_items() {
+            let hashes = self.item_hashes(&self.path(resource, id))?;
+
+            if !hashes.iter().any(|stored| stored == hash) && hashes.len() >= max_items {
+                return Err(ItemLimitReached(max_items).into());
+            }
+        }
+
+        let size = image_data.seek(SeekFrom::End(0))?;
+        image_data.rewind()?;
+
+        let reader = Reader::new(image_data).with_guessed_format()?;
+        let format = reader
+            .format()
+            .ok_or_else(|| anyhow!("Invalid file format"))?;
+        let mut image_data = reader.into_inner();
+
+        let animated = resource.animated() && is_animated(format, &mut image_data)?;
+
+        let (filename, files, (width, height), frame_count, crop) = match format {
+            ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP if animated => {
+                let decode_span =
+                    tracing::info_span!("decode", format = format.extensions_str()[0]).entered();
+                let (dimensions, frames) = decode_frames(format, image_data)?;
+                decode_span.exit();
+
+                // Frames are decoded while cropping, so only the cropped ones are kept
+                let crop_span = tracing::info_span!("crop").entered();
+                let mut cropped_frames = Vec::new();
+                let mut pixels = 0;
+
+                let mut first_frame_png: Option<RgbaImage> = None;
+                let mut crop = None;
+
+                for frame in frames {
+                    let frame = frame?;
+                    let delay = frame.delay();
+                    let dynamic_image = DynamicImage::ImageRgba8(frame.into_buffer());
+
+                    // Every frame is cropped like the first one
+                    let frame_crop = match crop {
+                        Some(crop) => crop,
+                        None => *crop.insert(Crop::resolve(
+                            &dynamic_image,
+                            resource.crop(),
+                            resource.aspect_ratio(),
+                            requested_crop,
+                        )?),
+                    };
+                    let cropped_image = frame_crop.apply(&dynamic_image, resource.aspect_ratio());
+                    pixels += cropped_image.width() as u64 * cropped_image.height() as u64;
+
+                    if cropped_frames.len() as u32 >= MAX_FRAME_COUNT
+                        || pixels > MAX_ANIMATION_PIXELS
+                    {
+                        return Err(AnimationTooLarge.into());
+                    }
+
+                    if first_frame_png.is_none() {
+                        first_frame_png = Some(cropped_image.to_rgba8());
+                    }
+
+                    cropped_frames.push(Frame::from_parts(cropped_image.to_rgba8(), 0, 0, delay));
+                }
+
+                let frame_count = cropped_frames.len() as u32;
+                crop_span.exit();
+
+                let _encode_span = tracing::info_span!("encode", format = "webp").entered();
+                let png_filename = format!("a_{hash}.png");
+                let animation_filename = format!("a_{hash}.webp");
+                let mut files = Vec::new();
+
+                // We want to show a still image until hover
+                if let Some(first_frame) = first_frame_png {
+                    let mut png_data = Vec::new();
+
+                    PngEncoder::new(&mut png_data).write_image(
+                        first_frame.as_raw(),
+                        first_frame.width(),
+                        first_frame.height(),
+                        image::ColorType::Rgba8,
+                    )?;
+
+                    files.push((png_filename.clone(), png_data));
+                }
+
+                let crop = crop.ok_or_else(|| anyhow!("Animation has no frames"))?;
+
+                // The animation is kept lossless, as GIFs and animated WebPs are both
+                // rendered from it
+                let (width, height) = cropped_frames[0].buffer().dimensions();
+                let mut config = webp::WebPConfig::new()
+                    .map_err(|_| anyhow!("Failed to configure WebP encoder"))?;
+                config.lossless = 1;
+                config.method = 0;
+
+                log::debug!("Encoding {frame_count} frames");
+
+                let animation_data = encode_animated_webp(&cropped_frames, width, height, &config)?;
+                files.push((animation_filename, animation_data));
+
+                (png_filename, files, dimensions, frame_count, crop)
+            }
+            // Resources that aren't animated keep the first frame of animations
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif => {
+                let filename = format!("{hash}.png");
+                let reader = Reader::with_format(image_data, format);
+                let image = tracing::info_span!("decode").in_scope(|| reader.decode())?;
+                let dimensions = image.dimensions();
+                let crop = Crop::resolve(
+                    &image,
+                    resource.crop(),
+                    resource.aspect_ratio(),
+                    requested_crop,
+                )?;
+                let cropped_image = tracing::info_span!("crop")
+                    .in_scope(|| crop.apply(&image, resource.aspect_ratio()));
+
+                let mut png_data = Vec::new();
+                tracing::info_span!("encode", format = "png")
+                    .in_scope(|| cropped_image.write_to(&mut Cursor::new(&mut png_data), Png))
+                    .map_err(|err| anyhow!("Failed to write image: {err}"))?;
+
+                (
+                    filename.clone(),
+                    vec![(filename, png_data)],
+                    dimensions,
+                    1,
+                    crop,
+                )
+            }
+            _ => return Err(anyhow!("Unsupported image format")),
+        };
+
+        let metadata = ResourceMetadata {
+            resource: resource.to_string(),
+            id: id.to_string(),
+            hash: hash.to_string(),
+            filename: filename.clone(),
+            format: format.extensions_str()[0].to_string(),
+            width,
+            height,
+            animated,
+            frame_count,
+            size,
+            uploaded_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            files: files.iter().map(|(filename, _)| filename.clone()).collect(),
+            crop: Some(crop),
+        };
+
+        let replaced = self.commit(resource, id, &metadata, files)?;
+
+        Ok(Stored { filename, replaced })
+    }
+
+    /// Returns the metadata of every resource stored for `id`, ordered by hash.
+    pub fn metadata(&self, resource: &Resource, id: &str) -> Result<Vec<ResourceMetadata>> {
+        self.list(resource, id, None, usize::MAX)
+    }
+
+    /// Returns the metadata of at most `limit` items stored for `id`, ordered by hash
+    /// and starting after the hash `after`.
+    pub fn list(
+        &self,
+        resource: &Resource,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ResourceMetadata>> {
+        let base_path = self.path(resource, id);
+
+        // Only the items on the page are read
+        self.item_hashes(&base_path)?
+            .iter()
+            .filter(|hash| after.is_none_or(|after| hash.as_str() > after))
+            .take(limit)
+            .filter_map(|hash| self.read_item(&base_path, hash).transpose())
+            .collect()
+    }
+
+    /// Removes an item of a non-singleton resource with all its files, returning
+    /// `false` if there is no item with `hash`.
+    pub fn delete(&self, resource: &Resource, id: &str, hash: &str) -> Result<bool> {
+        let base_path = self.path(resource, id);
+        let _lock = self.lock(&base_path)?;
+
+        let Some(metadata) = self.read_item(&base_path, hash)? else {
+            return Ok(false);
+        };
+
+        // The item is no longer listed before its files go away
+        self.backend.delete(&item_key(&base_path, hash))?;
+
+        let files = match metadata.files.is_empty() {
+            // Recorded before the files of a resource were
+            true => vec![metadata.filename.clone()],
+            false => metadata.files,
+        };
+
+        for filename in files {
+            self.backend.delete(&format!("{base_path}/{filename}"))?;
+        }
+
+        self.index.remove(&resource.to_string(), id, hash)?;
+
+        Ok(true)
+    }
+
+    /// Returns the versions of a singleton resource, the current one first.
+    pub fn versions(&self, resource: &Resource, id: &str) -> Result<Vec<Version>> {
+        self.read_manifest(&self.path(resource, id))
+    }
+
+    /// Makes a previous version of a singleton resource the current one again,
+    /// returning its filename, or `None` if no version with `hash` is kept.
+    ///
+    /// The versions uploaded after it are removed along with their files, so they
+    /// are no longer served.
+    pub fn rollback(&self, resource: &Resource, id: &str, hash: &str) -> Result<Option<String>> {
+        let base_path = self.path(resource, id);
+        let _lock = self.lock(&base_path)?;
+        let mut versions = self.read_manifest(&base_path)?;
+
+        let Some(index) = versions.iter().position(|version| version.hash == hash) else {
+            return Ok(None);
+        };
+
+        let dropped: Vec<Version> = versions.drain(..index).collect();
+
+        self.write_manifest(&base_path, &versions)?;
+
+        for version in &dropped {
+            self.backend.delete(&item_key(&base_path, &version.hash))?;
+        }
+
+        let kept: Vec<&String> = versions.iter().flat_map(|version| &version.files).collect();
+
+        for version in dropped {
+            for filename in version.files.iter().filter(|file| !kept.contains(file)) {
+                let key = format!("{base_path}/{filename}");
+
+                if self.backend.exists(&key)? {
+                    self.backend.delete(&key)?;
+                }
+            }
+
+            self.index
+                .remove(&resource.to_string(), id, &version.hash)?;
+        }
+
+        Ok(Some(versions[0].filename.clone()))
+    }
+
+    /// Removes leftovers of writes that were interrupted by a crash.
+    pub fn recover(&self) -> Result<usize> {
+        self.backend.recover()
+    }
+
+    /// Records the items of ids last written before items were kept in storage, which
+    /// are only known to the index, returning the number of items recorded.
+    ///
+    /// Items whose files no longer exist, e.g. as another instance removed them, are
+    /// skipped. This only runs once per index.
+    pub fn migrate_items(&self) -> Result<usize> {
+        if self.index.items_migrated()? {
+            return Ok(0);
+        }
+
+        let mut migrated = 0;
+
+        for metadata in self.index.all()? {
+            let base_path = format!("{}/{}", metadata.resource, metadata.id);
+            let _lock = self.lock(&base_path)?;
+
+            let key = item_key(&base_path, &metadata.hash);
+            let file = format!("{base_path}/{}", metadata.filename);
+
+            if !self.backend.exists(&key)? && self.backend.exists(&file)? {
+                self.write(&key, &serde_json::to_vec(&metadata)?)?;
+                migrated += 1;
+            }
+        }
+
+        self.index.mark_items_migrated()?;
+
+        Ok(migrated)
+    }
+
+    /// Checks that the backend accepts writes, by writing and removing a small object.
+    ///
+    /// Every check uses its own object, so concurrent checks don't remove each other's.
+    pub fn check_writable(&self) -> Result<()> {
+        let key = format!("{PROBE_PREFIX}-{}", uuid::Uuid::new_v4());
+
+        self.backend.put(&key, b"probe")?;
+        self.backend.delete(&key)
+    }
+
+    pub fn available_space(&self) -> Result<Option<u64>> {
+        self.backend.available_space()
+    }
+
+    /// Writes all `files` of a new upload, and only then removes the files it replaces.
+    ///
+    /// Each file is written atomically by the backend, so readers either see the previous
+    /// set of files or the new one. If any write fails, the files this upload created are
+    /// removed again. Files that already existed, such as those of an identical upload the
+    /// manifest still points to, are left untouched.
+    ///
+    /// Writes to the same id are serialized, so that concurrent uploads neither lose
+    /// manifest entries nor remove each other's files.
+    fn commit(
+        &self,
+        resource: &Resource,
+        id: &str,
+        metadata: &ResourceMetadata,
+        files: Vec<(String, Vec<u8>)>,
+    ) -> Result<bool> {
+        let base_path = &self.path(resource, id);
+        let (_id_lock, backend_lock) = self.lock(base_path)?;
+        let hashes = self.item_hashes(base_path)?;
+        let replaced = hashes.contains(&metadata.hash);
+
+        // Taken under the lock, so concurrent uploads can't exceed the limit together
+        if let (false, Some(max_items)) = (replaced, resource.max_items()) {
+            if hashes.len() >= max_items {
+                return Err(ItemLimitReached(max_items).into());
+            }
+        }
+
+        let mut created: Vec<String> = Vec::new();
+        let remove_created = |created: Vec<String>| {
+            for key in created {
+                let _ = self.backend.delete(&key);
+            }
+        };
+
+        for (filename, data) in files {
+            let key = format!("{base_path}/{filename}");
+            let written = self.backend.exists(&key).and_then(|existed| {
+                self.write(&key, &data)?;
+                Ok(existed)
+            });
+
+            match written {
+                Ok(true) => (),
+                Ok(false) => created.push(key),
+                Err(why) => {
+                    remove_created(created);
+
+                    return Err(why);
+                }
+            }
+        }
+
+        // Indexed before the item is listed, and undone if listing it fails, so that the
+        // index doesn't hold items storage doesn't. Nothing is listed if writing the files
+        // took so long that another instance may have taken over the id.
+        let previous = self.index.get(&metadata.resource, id, &metadata.hash)?;
+        let listed = backend_lock.ensure_held().and_then(|_| {
+            self.index.insert(metadata)?;
+            self.write(
+                &item_key(base_path, &metadata.hash),
+                &serde_json::to_vec(metadata)?,
+            )
+        });
+
+        if let Err(why) = listed {
+            let _ = match &previous {
+                Some(previous) => self.index.insert(previous),
+                None => self.index.remove(&metadata.resource, id, &metadata.hash),
+            };
+            remove_created(created);
+
+            return Err(why);
+        }
+
+        if resource.singleton() {
+            let version = Version {
+                hash: metadata.hash.clone(),
+                filename: metadata.filename.clone(),
+                format: metadata.format.clone(),
+                uploaded_at: metadata.uploaded_at,
+                files: metadata.files.clone(),
+            };
+            let mut versions = self.read_manifest(base_path)?;
+
+            versions.retain(|previous| previous.hash != version.hash);
+            versions.insert(0, version);
+
+            let dropped = versions.split_off(versions.len().min(self.history + 1));
+
+            self.write_manifest(base_path, &versions)?;
+
+            for version in dropped {
+                self.backend.delete(&item_key(base_path, &version.hash))?;
+                self.index
+                    .remove(&resource.to_string(), id, &version.hash)?;
+            }
+
+            let kept: Vec<String> = std::iter::once(MANIFEST_FILENAME.to_string())
+                .chain(versions.iter().flat_map(|version| version.files.clone()))
+                .map(|filename| format!("{base_path}/{filename}"))
+                .collect();
+
+            for key in self.backend.list(base_path)? {
+                if !kept.contains(&key) {
+                    self.backend
+                        .delete(&key)
+                        .map_err(|err| anyhow!("Failed to remove file: {err}"))?;
+                }
+            }
+        }
+
+        Ok(replaced)
+    }
+
+    /// Returns the hashes of the items stored for an id, in order.
+    fn item_hashes(&self, base_path: &str) -> Result<Vec<String>> {
+        let prefix = format!("{base_path}/{ITEMS_DIR}/");
+
+        let mut hashes: Vec<String> = self
+            .backend
+            .list(&format!("{base_path}/{ITEMS_DIR}"))?
+            .iter()
+            .filter_map(|key| key.strip_prefix(&prefix)?.strip_suffix(".json"))
+            .map(|hash| hash.to_string())
+            .collect();
+        hashes.sort();
+
+        Ok(hashes)
+    }
+
+    fn read_item(&self, base_path: &str, hash: &str) -> Result<Option<ResourceMetadata>> {
+        match self.backend.get(&item_key(base_path, hash))? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn read_manifest(&self, base_path: &str) -> Result<Vec<Version>> {
+        match self
+            .backend
+            .get(&format!("{base_path}/{MANIFEST_FILENAME}"))?
+        {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn write_manifest(&self, base_path: &str, versions: &[Version]) -> Result<()> {
+        let data = serde_json::to_vec(versions)?;
+
+        self.write(&format!("{base_path}/{MANIFEST_FILENAME}"), &data)
+    }
+
+    #[tracing::instrument(name = "storage_write", skip(self, data), fields(bytes = data.len()))]
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.backend.put(key, data)?;
+
+        METRICS.storage_bytes_written.inc_by(data.len() as u64);
+
+        Ok(())
+    }
+}
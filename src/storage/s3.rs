@@ -0,0 +1,269 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use http::header::{HeaderMap, HeaderValue, IF_MATCH, IF_NONE_MATCH};
+use s3::{creds::Credentials, Bucket, Region};
+
+use crate::config::S3Config;
+
+use super::{lock::BackendLock, StorageBackend};
+
+/// Prefix of the objects that lock ids.
+const LOCKS_PREFIX: &str = ".locks";
+
+/// How long a lock is held without being renewed, so that one left behind by a
+/// crashed instance doesn't block its id forever.
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+/// How often a held lock is renewed, leaving time for a few failed attempts.
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long taking a lock is retried before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+fn unix_time() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Stores resources in an S3-compatible object store (AWS S3, MinIO, ...).
+pub struct S3Backend {
+    bucket: Box<Bucket>,
+}
+
+impl S3Backend {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config.region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => config.region.parse()?,
+        };
+
+        let credentials = match (&config.access_key, &config.secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                Credentials::new(Some(access_key), Some(secret_key), None, None, None)?
+            }
+            _ => Credentials::default()?,
+        };
+
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(Self { bucket })
+    }
+
+    /// Deletes `key`, but only if the object still has `etag`.
+    fn delete_if_match(bucket: &Bucket, key: &str, etag: &str) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, HeaderValue::from_str(etag)?);
+
+        bucket.with_extra_headers(headers)?.delete_object(key)?;
+
+        Ok(())
+    }
+
+    /// Pushes the expiry of a held lock back, but only if it still has `etag`, which
+    /// is updated to that of the renewed lock. Returns `false` if it was taken over.
+    fn renew_lock(bucket: &Bucket, lock_key: &str, etag: &Mutex<Option<String>>) -> Result<bool> {
+        let mut etag = etag.lock().unwrap_or_else(|err| err.into_inner());
+        let mut headers = HeaderMap::new();
+
+        if let Some(etag) = etag.as_deref() {
+            headers.insert(IF_MATCH, HeaderValue::from_str(etag)?);
+        }
+
+        let expires_at = unix_time()? + LOCK_TTL.as_secs();
+        let response = bucket.put_object_with_headers(
+            lock_key,
+            expires_at.to_string().as_bytes(),
+            Some(headers),
+        )?;
+
+        match response.status_code() {
+            200 => {
+                *etag = response.headers().get("etag").cloned();
+                Ok(true)
+            }
+            412 => Ok(false),
+            code => Err(anyhow!("Unexpected status code {code} for PUT {lock_key}")),
+        }
+    }
+
+    /// Removes the lock object `lock_key` if it has expired.
+    fn clear_expired_lock(&self, lock_key: &str) -> Result<()> {
+        let response = self.bucket.get_object(lock_key)?;
+
+        // Released in the meantime
+        if response.status_code() != 200 {
+            return Ok(());
+        }
+
+        let expires_at: u64 = String::from_utf8_lossy(response.as_slice())
+            .trim()
+            .parse()
+            .unwrap_or(0);
+
+        if expires_at > unix_time()? {
+            return Ok(());
+        }
+
+        // Only the expired lock is removed, not one taken since it was read
+        match response.headers().get("etag") {
+            Some(etag) => Self::delete_if_match(&self.bucket, lock_key, etag),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.bucket.get_object(key)?;
+
+        match response.status_code() {
+            200 => Ok(Some(response.to_vec())),
+            404 => Ok(None),
+            code => Err(anyhow!("Unexpected status code {code} for GET {key}")),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let response = self.bucket.put_object(key, data)?;
+
+        match response.status_code() {
+            200 => Ok(()),
+            code => Err(anyhow!("Unexpected status code {code} for PUT {key}")),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let response = self.bucket.delete_object(key)?;
+
+        match response.status_code() {
+            // Some stores answer deletes of missing objects with 404
+            200 | 204 | 404 => Ok(()),
+            code => Err(anyhow!("Unexpected status code {code} for DELETE {key}")),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let results = self
+            .bucket
+            .list(format!("{prefix}/"), Some("/".to_string()))?;
+
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.bucket.object_exists(key)?)
+    }
+
+    /// Takes a lock by creating an object per key with a conditional PUT, which only
+    /// one of several instances sharing the bucket can win.
+    ///
+    /// The object holds the time the lock expires at, after which it may be taken over.
+    /// It is renewed in the background while held, so that slow writes keep it. Should
+    /// renewing fail until it expired, the lock reports itself as lost.
+    fn lock(&self, key: &str) -> Result<BackendLock> {
+        let lock_key = format!("{LOCKS_PREFIX}/{key}");
+        let started = Instant::now();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+
+        let (etag, taken_at) = loop {
+            let attempted_at = Instant::now();
+            let expires_at = unix_time()? + LOCK_TTL.as_secs();
+            let response = self.bucket.put_object_with_headers(
+                &lock_key,
+                expires_at.to_string().as_bytes(),
+                Some(headers.clone()),
+            )?;
+
+            match response.status_code() {
+                200 => break (response.headers().get("etag").cloned(), attempted_at),
+                // Held by another writer, or being taken at the same time
+                409 | 412 => self.clear_expired_lock(&lock_key)?,
+                code => return Err(anyhow!("Unexpected status code {code} for PUT {lock_key}")),
+            }
+
+            if started.elapsed() >= LOCK_TIMEOUT {
+                return Err(anyhow!("Timed out waiting for the lock on {key}"));
+            }
+
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        };
+
+        let etag = Arc::new(Mutex::new(etag));
+        let lost = Arc::new(AtomicBool::new(false));
+        let (release, released) = mpsc::channel::<()>();
+
+        let renewal = thread::spawn({
+            let bucket = self.bucket.clone();
+            let lock_key = lock_key.clone();
+            let etag = etag.clone();
+            let lost = lost.clone();
+
+            move || {
+                // The lock expires LOCK_TTL after the write that took or renewed it
+                let mut renewed_at = taken_at;
+
+                while let Err(RecvTimeoutError::Timeout) =
+                    released.recv_timeout(LOCK_RENEW_INTERVAL)
+                {
+                    let attempted_at = Instant::now();
+
+                    let held = match Self::renew_lock(&bucket, &lock_key, &etag) {
+                        Ok(true) => {
+                            renewed_at = attempted_at;
+                            true
+                        }
+                        Ok(false) => false,
+                        // Retried until the lock expired, and may have been taken over
+                        Err(why) => {
+                            log::warn!("Failed to renew {lock_key}: {why}");
+                            renewed_at.elapsed() < LOCK_TTL
+                        }
+                    };
+
+                    if !held {
+                        log::warn!("Lost {lock_key} to another instance");
+                        lost.store(true, Ordering::Release);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let bucket = self.bucket.clone();
+
+        Ok(BackendLock::new(move || {
+            drop(release);
+            let _ = renewal.join();
+
+            // A lock that expired may have been taken over, and mustn't be released
+            let etag = etag.lock().unwrap_or_else(|err| err.into_inner()).take();
+            let _ = match etag {
+                Some(etag) => Self::delete_if_match(&bucket, &lock_key, &etag),
+                None => bucket
+                    .delete_object(&lock_key)
+                    .map(|_| ())
+                    .map_err(Into::into),
+            };
+        })
+        .with_lost(lost))
+    }
+}
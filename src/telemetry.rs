@@ -0,0 +1,107 @@
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+};
+use anyhow::Result;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceContextExt, TracerProvider},
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::config::TracingConfig;
+
+/// Installs a `tracing` subscriber that exports spans over OTLP, and accepts W3C
+/// `traceparent` headers from callers.
+///
+/// Returns the provider, which has to be shut down to flush the remaining spans.
+pub fn init(config: &TracingConfig) -> Result<SdkTracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+
+    let tracer = provider.tracer("rs-cdn");
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(provider)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// The root span of a request, continuing the trace of the caller if it sent a
+/// `traceparent` header.
+pub fn request_span(request: &ServiceRequest, request_id: &str) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %request.method(),
+        http.request.method = %request.method(),
+        url.path = request.path(),
+        http.route = tracing::field::Empty,
+        request_id,
+        http.response.status_code = tracing::field::Empty,
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+
+    if parent.span().span_context().is_valid() {
+        let _ = span.set_parent(parent);
+    }
+
+    span
+}
+
+/// Names the span of a request after the route it matched, and records its status.
+pub fn record_response<B>(span: &Span, response: &ServiceResponse<B>) {
+    let request = response.request();
+
+    if let Some(route) = request.match_pattern() {
+        span.record("http.route", route.as_str());
+
+        // The span was already started, so the name can only be changed on the
+        // OpenTelemetry span itself
+        span.context()
+            .span()
+            .update_name(format!("{} {route}", request.method()));
+    }
+
+    span.record("http.response.status_code", response.status().as_u16());
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use std::sync::{LazyLock, Mutex, Once};
+
+use actix_web::{http::StatusCode, test};
+use log::{Log, Metadata, Record};
+use log4rs::encode::{writer::simple::SimpleWriter, Encode};
+use rs_cdn::{
+    access_log::{self, RequestIdEncoder, REQUEST_ID_HEADER},
+    config::CdnConfig,
+    rest,
+};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use common::*;
+
+/// Target of the log lines written by the tests, which are the only ones captured.
+const TARGET: &str = "request_id_test";
+
+/// Captures log lines as the `request_id` encoder writes them.
+struct CapturingLogger {
+    encoder: RequestIdEncoder,
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == TARGET
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut writer = SimpleWriter(Vec::new());
+        self.encoder.encode(&mut writer, record).unwrap();
+
+        self.lines
+            .lock()
+            .unwrap()
+            .push(String::from_utf8(writer.0).unwrap());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: LazyLock<CapturingLogger> = LazyLock::new(|| CapturingLogger {
+    encoder: RequestIdEncoder::new("{m}"),
+    lines: Mutex::new(Vec::new()),
+});
+
+fn logged(line: &str) -> bool {
+    LOGGER
+        .lines
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|logged| logged == line)
+}
+
+fn capture_logs() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        log::set_logger(&*LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+    });
+}
+
+#[actix_web::test]
+async fn logs_of_blocking_calls_carry_the_request_id() {
+    capture_logs();
+
+    let logged_in_request = access_log::scope(
+        "request-1".to_string(),
+        rest::blocking(|| {
+            log::info!(target: TARGET, "Blocking call of a request");
+            Ok(access_log::current_request_id())
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(logged_in_request.as_deref(), Some("request-1"));
+
+    rest::blocking(|| {
+        log::info!(target: TARGET, "Blocking call outside of requests");
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    assert!(logged("[request-1] Blocking call of a request"));
+    assert!(logged("Blocking call outside of requests"));
+}
+
+#[actix_web::test]
+async fn request_ids_are_echoed_or_generated() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+
+    let request_id = |sent: Option<&str>| {
+        let app = &app;
+        let sent = sent.map(|id| id.to_string());
+
+        async move {
+            let mut request = test::TestRequest::get().uri("/healthz");
+
+            if let Some(sent) = sent {
+                request = request.insert_header((REQUEST_ID_HEADER, sent));
+            }
+
+            let response = test::call_service(app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            response
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    assert_eq!(request_id(Some("abc-123.4_5")).await, "abc-123.4_5");
+
+    // Ids are generated for requests without one, or with one that can't be logged as is
+    for sent in [
+        None,
+        Some(""),
+        Some("a b"),
+        Some("a\"b"),
+        Some(&*"a".repeat(129)),
+    ] {
+        let received = request_id(sent).await;
+
+        assert!(Uuid::parse_str(&received).is_ok(), "{sent:?}: {received}");
+    }
+
+    assert_ne!(request_id(None).await, request_id(None).await);
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{http::StatusCode, test};
+use rs_cdn::config::CdnConfig;
+use tempfile::TempDir;
+
+use common::*;
+
+#[actix_web::test]
+async fn cache_key_signatures_expire() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    for (timestamp, signed, status) in [
+        (Some(now), now, StatusCode::OK),
+        (Some(now - 60), now - 60, StatusCode::OK),
+        // Replayed long after it was signed
+        (Some(now - 3600), now - 3600, StatusCode::UNAUTHORIZED),
+        // Signed for another time than it claims
+        (Some(now), now - 3600, StatusCode::UNAUTHORIZED),
+        (None, now, StatusCode::BAD_REQUEST),
+    ] {
+        let message = format!("admin:cache-keys:{signed}");
+        let mut request = test::TestRequest::get()
+            .uri("/admin/cache/keys")
+            .insert_header(("X-Signature", sign(message.as_bytes())));
+
+        if let Some(timestamp) = timestamp {
+            request = request.insert_header(("X-Signature-Timestamp", timestamp.to_string()));
+        }
+
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(
+            response.status(),
+            status,
+            "{timestamp:?} signed for {signed}"
+        );
+    }
+}
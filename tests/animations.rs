@@ -0,0 +1,374 @@
+mod common;
+
+use std::io::Cursor;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder},
+        webp::WebPDecoder,
+    },
+    AnimationDecoder, Delay, Frame, GenericImageView, Rgba, RgbaImage,
+};
+use rs_cdn::config::CdnConfig;
+use serde_json::Value;
+use tempfile::TempDir;
+
+use common::*;
+
+/// Whether a pixel is close to red, as lossy animations aren't exactly red.
+fn is_red(pixel: Rgba<u8>) -> bool {
+    pixel[0] > 200 && pixel[1] < 50 && pixel[2] < 50
+}
+
+fn is_blue(pixel: Rgba<u8>) -> bool {
+    pixel[0] < 50 && pixel[1] < 50 && pixel[2] > 200
+}
+
+/// An APNG of two 64x64 frames of gradients, which have more colors than a GIF could hold.
+fn gradient_png() -> (Vec<u8>, [RgbaImage; 2]) {
+    let frames = [0, 1].map(|frame| {
+        RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([
+                (x * 4) as u8,
+                (y * 4) as u8,
+                ((x + y) * 2 + frame * 64) as u8,
+                255,
+            ])
+        })
+    });
+    let mut data = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(&mut data, 64, 64);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 0).unwrap();
+        encoder.set_frame_delay(1, 10).unwrap();
+
+        let mut writer = encoder.write_header().unwrap();
+
+        for frame in &frames {
+            writer.write_image_data(frame.as_raw()).unwrap();
+        }
+
+        writer.finish().unwrap();
+    }
+
+    (data, frames)
+}
+
+/// A GIF of `count` frames of `size` by `size` pixels, alternating between black and white
+/// so that they can't be merged.
+fn long_gif(count: usize, size: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    {
+        let mut encoder = GifEncoder::new(&mut data);
+
+        for index in 0..count {
+            let shade = (index % 2 * 255) as u8;
+
+            encoder
+                .encode_frame(Frame::from_parts(
+                    RgbaImage::from_pixel(size, size, Rgba([shade, shade, shade, 255])),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(10, 1),
+                ))
+                .unwrap();
+        }
+    }
+
+    data
+}
+
+fn animations() -> [(&'static str, Vec<u8>); 3] {
+    [
+        ("gif", animated_gif()),
+        ("png", animated_png()),
+        ("webp", animated_webp()),
+    ]
+}
+
+#[actix_web::test]
+async fn animations_are_processed_in_every_format() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let avatars = resource("avatars");
+
+    for (format, animation) in animations() {
+        let filename = upload_filename(&app, &avatars, format, &animation).await;
+        assert!(filename.starts_with("a_"), "{format} stored as {filename}");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/avatars/{format}/{filename}?size=128"))
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        let still = image::load_from_memory(&body).unwrap();
+
+        assert_eq!(still.dimensions(), (128, 128));
+        assert!(is_red(still.get_pixel(64, 64)), "still of {format}");
+
+        let request = test::TestRequest::get()
+            .uri(&format!(
+                "/avatars/{format}/{}?size=128",
+                filename.replace(".png", ".gif")
+            ))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/gif"
+        );
+
+        let body = test::read_body(response).await;
+        let frames = GifDecoder::new(Cursor::new(body.to_vec()))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+
+        assert_eq!(frames.len(), 2, "frames of {format}");
+        assert!(is_red(*frames[0].buffer().get_pixel(64, 64)));
+        assert!(is_blue(*frames[1].buffer().get_pixel(64, 64)));
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/avatars/{format}"))
+            .to_request();
+        let items: Value = test::call_and_read_body_json(&app, request).await;
+        let item = &items["items"][0];
+
+        assert_eq!(item["animated"], true);
+        assert_eq!(item["frame_count"], 2);
+        assert_eq!(item["format"], format);
+    }
+}
+
+#[actix_web::test]
+async fn resources_that_arent_animated_keep_the_first_frame() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let icons = resource("icons");
+
+    for (format, animation) in animations() {
+        let filename = upload_filename(&app, &icons, format, &animation).await;
+        assert!(!filename.starts_with("a_"), "{format} stored as {filename}");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/icons/{format}/{filename}?size=128"))
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        let still = image::load_from_memory(&body).unwrap();
+
+        assert!(is_red(still.get_pixel(64, 64)), "still of {format}");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/icons/{format}/a_{filename}?size=128"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
+
+#[actix_web::test]
+async fn animations_are_served_as_webp() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", &animated_gif()).await;
+    let hash = filename.trim_end_matches(".png");
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{hash}.webp?size=1024"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/webp"
+    );
+
+    let body = test::read_body(response).await;
+    let frames = WebPDecoder::new(Cursor::new(body.to_vec()))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].buffer().dimensions(), (1024, 1024));
+    assert!(is_red(*frames[0].buffer().get_pixel(512, 512)));
+    assert!(is_blue(*frames[1].buffer().get_pixel(512, 512)));
+
+    for frame in &frames {
+        assert_eq!(frame.delay().numer_denom_ms(), (100, 1));
+    }
+
+    // GIFs are negotiated, and only WebPs may be this large
+    for (accept, size, status, content_type) in [
+        (None, 128, StatusCode::OK, Some("image/gif")),
+        (None, 512, StatusCode::BAD_REQUEST, None),
+        (Some("image/webp"), 512, StatusCode::OK, Some("image/webp")),
+        (Some("image/*"), 128, StatusCode::OK, Some("image/gif")),
+    ] {
+        let mut request =
+            test::TestRequest::get().uri(&format!("/avatars/1/{hash}.gif?size={size}"));
+
+        if let Some(accept) = accept {
+            request = request.insert_header((header::ACCEPT, accept));
+        }
+
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), status, "{accept:?} at {size}");
+
+        if let Some(content_type) = content_type {
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                content_type
+            );
+            assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
+        }
+    }
+
+    // The still stays a still
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{filename}?size=128"))
+        .insert_header((header::ACCEPT, "image/webp"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let decoder = WebPDecoder::new(Cursor::new(body.to_vec())).unwrap();
+
+    assert!(!decoder.has_animation());
+}
+
+#[actix_web::test]
+async fn every_frame_keeps_its_delay() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let delays = [100, 200, 700];
+    let mut gif = Vec::new();
+
+    {
+        let mut encoder = GifEncoder::new(&mut gif);
+
+        for (index, delay) in delays.iter().enumerate() {
+            let color = [RED, BLUE][index % 2];
+
+            encoder
+                .encode_frame(Frame::from_parts(
+                    RgbaImage::from_pixel(64, 64, color),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(*delay, 1),
+                ))
+                .unwrap();
+        }
+    }
+
+    let filename = upload_filename(&app, &resource("avatars"), "1", &gif).await;
+    let hash = filename.trim_end_matches(".png");
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{hash}.webp?size=128"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let frames = WebPDecoder::new(Cursor::new(body.to_vec()))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+
+    // The last one included, which the encoder would otherwise cut short
+    let served: Vec<u32> = frames
+        .iter()
+        .map(|frame| frame.delay().numer_denom_ms().0)
+        .collect();
+    assert_eq!(served, delays);
+}
+
+#[actix_web::test]
+async fn animations_are_stored_losslessly() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let (animation, frames) = gradient_png();
+
+    let filename = upload_filename(&app, &resource("avatars"), "1", &animation).await;
+    let files = storage_dir.path().join("files/avatars/1");
+    let stored = std::fs::read(files.join(filename.replace(".png", ".webp"))).unwrap();
+    let stored_frames = WebPDecoder::new(Cursor::new(stored))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+
+    assert!(!files.join(filename.replace(".png", ".gif")).exists());
+    assert_eq!(stored_frames.len(), 2);
+
+    for (stored, frame) in stored_frames.iter().zip(&frames) {
+        assert_eq!(stored.buffer(), frame);
+    }
+}
+
+#[actix_web::test]
+async fn animations_stored_as_gif_are_still_served() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", &animated_gif()).await;
+    let hash = filename.trim_end_matches(".png");
+
+    // As uploaded before animations were stored as WebP
+    let files = storage_dir.path().join("files/avatars/1");
+    std::fs::remove_file(files.join(format!("{hash}.webp"))).unwrap();
+    std::fs::write(files.join(format!("{hash}.gif")), animated_gif()).unwrap();
+
+    for format in ["gif", "webp"] {
+        let request = test::TestRequest::get()
+            .uri(&format!("/avatars/1/{hash}.{format}?size=128"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK, "{format}");
+
+        let body = test::read_body(response).await;
+        let frames = match format {
+            "gif" => GifDecoder::new(Cursor::new(body.to_vec()))
+                .unwrap()
+                .into_frames(),
+            _ => WebPDecoder::new(Cursor::new(body.to_vec()))
+                .unwrap()
+                .into_frames(),
+        }
+        .collect_frames()
+        .unwrap();
+
+        assert_eq!(frames.len(), 2, "{format}");
+        assert!(is_red(*frames[0].buffer().get_pixel(64, 64)), "{format}");
+        assert!(is_blue(*frames[1].buffer().get_pixel(64, 64)), "{format}");
+    }
+}
+
+#[actix_web::test]
+async fn animations_with_too_many_frames_are_rejected() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let avatars = resource("avatars");
+
+    let response = upload(&app, &avatars, "1", &long_gif(1001, 1)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // 200 frames fit in storage, but not at 1024x1024
+    let filename = upload_filename(&app, &avatars, "2", &long_gif(200, 8)).await;
+    let hash = filename.trim_end_matches(".png");
+
+    for (size, status) in [(128, StatusCode::OK), (1024, StatusCode::BAD_REQUEST)] {
+        let request = test::TestRequest::get()
+            .uri(&format!("/avatars/2/{hash}.webp?size={size}"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), status, "at {size}");
+    }
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use std::io::Cursor;
+
+use actix_web::{http::StatusCode, test};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, GenericImageView};
+use rs_cdn::{
+    config::{AspectRatio, CdnConfig, CropMode, ResourceConfig},
+    rest::Resource,
+};
+use serde_json::Value;
+use tempfile::TempDir;
+
+use common::*;
+
+fn banners() -> ResourceConfig {
+    ResourceConfig {
+        aspect_ratio: AspectRatio {
+            width: 16,
+            height: 9,
+        },
+        sizes: vec![256, 320, 640],
+        default_size: 640,
+        ..ResourceConfig::new("banners")
+    }
+}
+
+fn covers() -> ResourceConfig {
+    ResourceConfig {
+        aspect_ratio: AspectRatio {
+            width: 3,
+            height: 1,
+        },
+        sizes: vec![300, 600],
+        default_size: 600,
+        crop: CropMode::Contain,
+        ..ResourceConfig::new("covers")
+    }
+}
+
+fn config() -> CdnConfig {
+    CdnConfig {
+        resources: vec![banners(), covers()],
+        ..CdnConfig::default()
+    }
+}
+
+#[actix_web::test]
+async fn sizes_are_widths_of_the_aspect_ratio() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let banners = Resource::new(banners());
+
+    let filename = upload_filename(&app, &banners, "1", ORANGE).await;
+
+    for (query, dimensions) in [
+        ("", (640, 360)),
+        ("?size=320", (320, 180)),
+        ("?size=256", (256, 144)),
+    ] {
+        let request = test::TestRequest::get()
+            .uri(&format!("/banners/1/{filename}{query}"))
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        let image = image::load_from_memory(&body).unwrap();
+
+        assert_eq!(
+            image.dimensions(),
+            dimensions,
+            "banner requested with {query:?}"
+        );
+    }
+
+    let request = test::TestRequest::get().uri("/banners/1").to_request();
+    let items: Value = test::call_and_read_body_json(&app, request).await;
+    let crop = &items["items"][0]["crop"];
+    let (width, height) = (
+        crop["width"].as_u64().unwrap(),
+        crop["height"].as_u64().unwrap(),
+    );
+    assert!((width * 9).abs_diff(height * 16) <= 16, "{crop}");
+
+    for (crop, status) in [
+        ("0,0,160,90", StatusCode::CREATED),
+        ("0,0,100,100", StatusCode::BAD_REQUEST),
+    ] {
+        let response = upload_with(&app, &banners, "2", ORANGE, &[("crop", crop)]).await;
+        assert_eq!(response.status(), status, "crop {crop}");
+    }
+}
+
+#[actix_web::test]
+async fn animations_get_the_aspect_ratio() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let banners = Resource::new(banners());
+
+    let filename = upload_filename(&app, &banners, "1", &animated_gif()).await;
+    let animation = filename.replace(".png", ".gif");
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/banners/1/{animation}?size=256"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let frames = GifDecoder::new(Cursor::new(body.to_vec()))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].buffer().dimensions(), (256, 144));
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/banners/1/{filename}?size=256"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let still = image::load_from_memory(&body).unwrap();
+
+    assert_eq!(still.dimensions(), (256, 144));
+}
+
+#[actix_web::test]
+async fn contained_images_are_padded_to_the_aspect_ratio() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let covers = Resource::new(covers());
+
+    // Already 3:1, so nothing is padded
+    let filename = upload_filename(&app, &covers, "1", &stripes(false)).await;
+    let request = test::TestRequest::get()
+        .uri(&format!("/covers/1/{filename}?size=300"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let image = image::load_from_memory(&body).unwrap();
+
+    assert_eq!(image.dimensions(), (300, 100));
+    assert_eq!(image.get_pixel(50, 50), RED);
+    assert_eq!(image.get_pixel(150, 50), GREEN);
+    assert_eq!(image.get_pixel(250, 50), BLUE);
+
+    // A square is padded on both sides
+    let filename = upload_filename(&app, &covers, "2", ORANGE).await;
+    let request = test::TestRequest::get()
+        .uri(&format!("/covers/2/{filename}?size=300"))
+        .to_request();
+    let body = test::call_and_read_body(&app, request).await;
+    let image = image::load_from_memory(&body).unwrap();
+
+    assert_eq!(image.dimensions(), (300, 100));
+    assert_eq!(image.get_pixel(10, 50).0[3], 0);
+    assert_eq!(image.get_pixel(290, 50).0[3], 0);
+    assert_eq!(image.get_pixel(150, 50).0[3], 255);
+}
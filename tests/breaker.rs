@@ -0,0 +1,46 @@
+use std::{thread, time::Duration};
+
+use rs_cdn::breaker::{BreakerState, CircuitBreaker};
+
+const COOLDOWN: Duration = Duration::from_millis(50);
+
+#[test]
+fn breakers_open_after_consecutive_failures() {
+    let breaker = CircuitBreaker::new(2, COOLDOWN);
+
+    breaker.record_failure();
+    assert!(breaker.allow());
+
+    breaker.record_failure();
+    assert_eq!(breaker.state(), BreakerState::Open);
+    assert!(!breaker.allow());
+
+    thread::sleep(COOLDOWN);
+
+    assert!(breaker.allow());
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+    breaker.record_success();
+    assert_eq!(breaker.state(), BreakerState::Closed);
+}
+
+#[test]
+fn trials_that_never_report_back_are_retried() {
+    let breaker = CircuitBreaker::new(1, COOLDOWN);
+
+    breaker.record_failure();
+    thread::sleep(COOLDOWN);
+
+    // The trial call is dropped before recording its outcome
+    assert!(breaker.allow());
+    assert!(!breaker.allow());
+
+    thread::sleep(COOLDOWN);
+
+    assert!(breaker.allow());
+    assert!(!breaker.allow());
+
+    breaker.record_failure();
+    assert_eq!(breaker.state(), BreakerState::Open);
+    assert!(!breaker.allow());
+}
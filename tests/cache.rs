@@ -0,0 +1,190 @@
+mod common;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use rs_cdn::{
+    cache::{
+        memory::MemoryCache, policy::CachePolicy, Cache, CacheBackend, CacheKey, CacheTier, Health,
+        LOCK_TTL,
+    },
+    config::{CacheConfig, CdnConfig},
+};
+use tempfile::TempDir;
+
+use common::*;
+
+/// A cache whose render locks are always taken by another instance, which has
+/// released them without caching anything by the time they are checked again.
+struct ContestedCache(MemoryCache);
+
+#[async_trait]
+impl CacheBackend for ContestedCache {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        self.0.get(key).await
+    }
+
+    async fn put(&self, key: &str, value: Bytes, ttl: Duration) -> Result<()> {
+        self.0.put(key, value, ttl).await
+    }
+
+    async fn health(&self) -> Result<Health> {
+        self.0.health().await
+    }
+
+    async fn add_to_index(&self, index: &str, key: &str, ttl: Duration) -> Result<()> {
+        self.0.add_to_index(index, key, ttl).await
+    }
+
+    async fn purge_index(&self, index: &str) -> Result<()> {
+        self.0.purge_index(index).await
+    }
+
+    async fn scan(&self, prefix: &str, cursor: u64, count: usize) -> Result<(u64, Vec<String>)> {
+        self.0.scan(prefix, cursor, count).await
+    }
+
+    async fn try_lock(&self, _key: &str, _ttl: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn is_locked(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+#[actix_web::test]
+async fn released_locks_arent_waited_for() {
+    let storage_dir = TempDir::new().unwrap();
+    let cache = Arc::new(ContestedCache(MemoryCache::new(64 * 1024 * 1024)));
+    let app = app_with_cache(&storage_dir, CdnConfig::default(), cache).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", ORANGE).await;
+
+    for (path, status) in [
+        (format!("/avatars/1/{filename}?size=128"), StatusCode::OK),
+        (
+            format!("/avatars/1/{}.png?size=128", "f".repeat(40)),
+            StatusCode::NOT_FOUND,
+        ),
+    ] {
+        let started_at = Instant::now();
+        let request = test::TestRequest::get().uri(&path).to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), status, "{path}");
+        assert!(
+            started_at.elapsed() < LOCK_TTL,
+            "{path} waited for the lock"
+        );
+
+        if status == StatusCode::OK {
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                "image/png"
+            );
+        }
+    }
+}
+
+#[actix_web::test]
+async fn repeated_hits_are_served_from_memory_until_purged() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let avatars = resource("avatars");
+    let filename = upload_filename(&app, &avatars, "1", ORANGE).await;
+
+    let origin_status = |path: String| {
+        let app = &app;
+
+        async move {
+            let request = test::TestRequest::get().uri(&path).to_request();
+            let response = test::call_service(app, request).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+
+            response.headers().get("X-Origin-Status").unwrap().clone()
+        }
+    };
+    let path = format!("/avatars/1/{filename}?size=128");
+
+    assert_eq!(origin_status(path.clone()).await, "origin");
+    assert_eq!(origin_status(path.clone()).await, "memory");
+    assert_eq!(origin_status(path.clone()).await, "memory");
+
+    // Replacing the avatar purges the renditions of the previous one, which is still
+    // kept as a version
+    upload_filename(&app, &avatars, "1", &stripes(false)).await;
+
+    assert_eq!(origin_status(path.clone()).await, "origin");
+    assert_eq!(origin_status(path).await, "memory");
+}
+
+/// Two instances with an in-process cache each, sharing a backend.
+fn instances() -> (Arc<MemoryCache>, Cache, Cache) {
+    let backend = Arc::new(MemoryCache::new(64 * 1024 * 1024));
+    let instance = || {
+        Cache::new(
+            Some(MemoryCache::new(1024 * 1024)),
+            backend.clone(),
+            CachePolicy::new(&CacheConfig::default()),
+        )
+    };
+
+    (backend.clone(), instance(), instance())
+}
+
+#[actix_web::test]
+async fn purges_reach_every_instance() {
+    let (_, first, second) = instances();
+    let avatars = resource("avatars");
+    let key = CacheKey::new(&avatars, "1", &"a".repeat(40), "png", 128);
+
+    first.put(&key, Bytes::from_static(b"old")).await.unwrap();
+
+    let tiers = [CacheTier::Cache, CacheTier::Memory];
+    for tier in tiers {
+        let (value, found_in) = second.get(&key).await.unwrap();
+        assert_eq!(value, "old");
+        assert_eq!(found_in, tier);
+    }
+
+    first.purge(&avatars, "1").await.unwrap();
+    assert!(second.get(&key).await.is_none());
+
+    // Values cached after the purge are found again
+    first.put(&key, Bytes::from_static(b"new")).await.unwrap();
+    for tier in tiers {
+        let (value, found_in) = second.get(&key).await.unwrap();
+        assert_eq!(value, "new");
+        assert_eq!(found_in, tier);
+    }
+}
+
+#[actix_web::test]
+async fn promoted_values_expire_with_the_backend() {
+    let (backend, _, cache) = instances();
+    let key = CacheKey::new(&resource("avatars"), "1", &"a".repeat(40), "png", 128);
+
+    // Cached long ago by another instance, under a TTL of five minutes
+    let encoded = CachePolicy::new(&CacheConfig::default())
+        .encode(b"value")
+        .unwrap();
+    backend
+        .put(&key.to_string(), encoded, Duration::from_millis(200))
+        .await
+        .unwrap();
+
+    assert_eq!(cache.get(&key).await.unwrap().1, CacheTier::Cache);
+    assert_eq!(cache.get(&key).await.unwrap().1, CacheTier::Memory);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(cache.get(&key).await.is_none());
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use rs_cdn::{
+    cache::{policy::CachePolicy, CacheKey},
+    config::{CacheCompression, CacheConfig, CacheRule, ResourceConfig},
+    rest::Resource,
+};
+
+fn key(resource: &str, size: u32) -> CacheKey {
+    let resource = Resource::new(ResourceConfig::new(resource));
+
+    CacheKey::new(&resource, "1", &"a".repeat(40), "png", size)
+}
+
+fn policy() -> CachePolicy {
+    CachePolicy::new(&CacheConfig {
+        ttl: 60,
+        rules: vec![
+            CacheRule {
+                resource: Some("avatars".to_string()),
+                size: Some(64),
+                ttl: 10,
+            },
+            CacheRule {
+                resource: Some("avatars".to_string()),
+                size: None,
+                ttl: 20,
+            },
+            CacheRule {
+                resource: None,
+                size: Some(64),
+                ttl: 30,
+            },
+        ],
+        skip_sizes: vec![16],
+        max_object_size: 4,
+        ..CacheConfig::default()
+    })
+}
+
+fn ttl(policy: &CachePolicy, resource: &str, size: u32) -> Option<Duration> {
+    policy.rule(&key(resource, size)).map(|rule| rule.ttl())
+}
+
+#[test]
+fn the_first_matching_rule_applies() {
+    let policy = policy();
+
+    assert_eq!(ttl(&policy, "avatars", 64), Some(Duration::from_secs(10)));
+    assert_eq!(ttl(&policy, "avatars", 128), Some(Duration::from_secs(20)));
+    assert_eq!(ttl(&policy, "banners", 64), Some(Duration::from_secs(30)));
+    assert_eq!(ttl(&policy, "banners", 128), Some(Duration::from_secs(60)));
+
+    assert_eq!(policy.max_ttl(), Duration::from_secs(60));
+}
+
+#[test]
+fn skipped_sizes_and_large_objects_are_not_cached() {
+    let policy = policy();
+
+    // Skipped sizes take precedence over every rule
+    assert!(policy.rule(&key("avatars", 16)).is_none());
+    assert!(policy.rule(&key("banners", 16)).is_none());
+
+    assert!(policy.fits(b"1234"));
+    assert!(!policy.fits(b"12345"));
+}
+
+#[test]
+fn values_survive_being_encoded() {
+    let value = b"rendered image ".repeat(64);
+
+    for compression in [CacheCompression::None, CacheCompression::Deflate] {
+        let policy = CachePolicy::new(&CacheConfig {
+            compression,
+            ..CacheConfig::default()
+        });
+
+        let encoded = policy.encode(&value).unwrap();
+        assert_eq!(policy.decode(encoded.clone()).unwrap(), value);
+
+        // Values stay readable after the compression setting changed
+        let other = CachePolicy::new(&CacheConfig::default());
+        assert_eq!(other.decode(encoded).unwrap(), value);
+    }
+
+    let deflate = CachePolicy::new(&CacheConfig {
+        compression: CacheCompression::Deflate,
+        ..CacheConfig::default()
+    });
+    assert!(deflate.encode(&value).unwrap().len() < value.len());
+}
+
+#[test]
+fn untagged_values_are_rejected() {
+    let policy = CachePolicy::new(&CacheConfig::default());
+
+    // Cached before values were tagged with their encoding, i.e. a bare PNG
+    let legacy = Bytes::from_static(b"\x89PNG\r\n\x1a\n");
+    assert!(policy.decode(legacy).is_err());
+    assert!(policy.decode(Bytes::new()).is_err());
+
+    // A deflate tag followed by data that isn't deflated
+    assert!(policy.decode(Bytes::from_static(b"\x01\xff\xff")).is_err());
+}
+
+#[test]
+fn stats_are_counted_per_rule() {
+    let policy = policy();
+
+    let rule = policy.rule(&key("avatars", 64)).unwrap();
+    rule.record_hit();
+    rule.record_hit();
+    rule.record_hit();
+    rule.record_miss();
+    rule.record_stored();
+    rule.record_too_large();
+
+    policy.rule(&key("banners", 128)).unwrap().record_miss();
+    policy.record_skipped();
+
+    let stats = policy.stats();
+    let names: Vec<&str> = stats.rules.iter().map(|rule| rule.name.as_str()).collect();
+    assert_eq!(names, ["avatars:64", "avatars:*", "*:64", "default"]);
+
+    let avatars = &stats.rules[0];
+    assert_eq!((avatars.hits, avatars.misses), (3, 1));
+    assert_eq!(avatars.hit_ratio, 0.75);
+    assert_eq!((avatars.stored, avatars.too_large), (1, 1));
+
+    let default = &stats.rules[3];
+    assert_eq!((default.hits, default.misses, default.ttl), (0, 1, 60));
+    assert_eq!(default.hit_ratio, 0.0);
+
+    // Rules without lookups have no ratio to speak of
+    assert_eq!(stats.rules[1].hit_ratio, 0.0);
+    assert_eq!(stats.skipped, 1);
+}
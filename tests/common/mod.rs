@@ -0,0 +1,355 @@
+//! Helpers shared by the end-to-end tests.
+#![allow(dead_code)]
+
+use std::{
+    io::Cursor,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::{header, StatusCode},
+    test, web, App,
+};
+use base64::{engine::general_purpose, Engine};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, DynamicImage, Frame, ImageOutputFormat, Rgba, RgbaImage,
+};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use rs_cdn::{
+    cache::{memory::MemoryCache, policy::CachePolicy, Cache, CacheBackend},
+    cdn::{Cdn, Connected},
+    config::{CacheConfig, CdnConfig},
+    index::MetadataIndex,
+    rest::{self, Resource},
+    storage::{fs::FilesystemBackend, Storage},
+};
+use serde_json::Value;
+use tempfile::TempDir;
+
+pub const BOUNDARY: &str = "rs-cdn-test-boundary";
+pub const ORANGE: &[u8] = include_bytes!("../../assets/orange.jpg");
+
+/// Builds the app on top of a temporary storage directory and an in-memory cache.
+pub async fn app(
+    storage_dir: &TempDir,
+    config: CdnConfig,
+) -> impl Service<
+    actix_http::Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    app_with_cache(
+        storage_dir,
+        config,
+        Arc::new(MemoryCache::new(64 * 1024 * 1024)),
+    )
+    .await
+}
+
+/// Builds the app on top of a temporary storage directory and the given cache backend,
+/// with an in-process cache in front of it unless `l1_capacity` is 0.
+pub async fn app_with_cache(
+    storage_dir: &TempDir,
+    config: CdnConfig,
+    cache_backend: Arc<dyn CacheBackend>,
+) -> impl Service<
+    actix_http::Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    app_with_cdn(cdn(storage_dir, config, cache_backend)).await
+}
+
+/// Builds the state of the app, see [`app_with_cache`].
+pub fn cdn(
+    storage_dir: &TempDir,
+    config: CdnConfig,
+    cache_backend: Arc<dyn CacheBackend>,
+) -> Arc<Cdn<Connected>> {
+    std::env::set_var(
+        "PUBLIC_KEY_PATH",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/certificates/staging.pub"),
+    );
+
+    let root = storage_dir.path();
+    let backend = Arc::new(FilesystemBackend::new(root.join("files").to_str().unwrap()));
+    let index = MetadataIndex::open(root.join("index").to_str().unwrap()).unwrap();
+    let storage = Storage::new(backend, index, 5);
+
+    let memory = match config.cache.l1_capacity {
+        0 => None,
+        capacity => Some(MemoryCache::new(capacity)),
+    };
+    let cache = Cache::new(
+        memory,
+        cache_backend,
+        CachePolicy::new(&CacheConfig::default()),
+    );
+
+    Arc::new(Cdn::new(storage, cache, config).connect())
+}
+
+/// Builds the app serving `cdn`, for tests that need to reach into its state.
+pub async fn app_with_cdn(
+    cdn: Arc<Cdn<Connected>>,
+) -> impl Service<
+    actix_http::Request,
+    Response = ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    let resources = cdn.resources.clone();
+
+    test::init_service(
+        App::new()
+            .wrap_fn(rest::observe)
+            .app_data(web::Data::new(cdn))
+            .configure(|cfg| rest::configure_routes(cfg, &resources)),
+    )
+    .await
+}
+
+/// The resources that are served by default.
+pub fn resources() -> Vec<Resource> {
+    CdnConfig::default()
+        .resources
+        .into_iter()
+        .map(Resource::new)
+        .collect()
+}
+
+pub fn resource(name: &str) -> Resource {
+    resources()
+        .into_iter()
+        .find(|resource| resource.name() == name)
+        .unwrap()
+}
+
+pub fn sign(data: &[u8]) -> String {
+    let pem = include_bytes!("../../certificates/staging.pem");
+    let key = PKey::private_key_from_pem(pem).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).unwrap();
+    signer.update(data).unwrap();
+
+    general_purpose::STANDARD.encode(signer.sign_to_vec().unwrap())
+}
+
+/// The current time in seconds since the unix epoch, as sent with signed requests.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub fn multipart(image: &[u8], signature: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    body.extend_from_slice(
+        format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"image\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(image);
+    body.extend_from_slice(
+        format!(
+            "\r\n--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"signature\"\r\n\r\n\
+             {signature}\r\n"
+        )
+        .as_bytes(),
+    );
+
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+
+    body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+
+    body
+}
+
+pub async fn upload<S, B>(
+    app: &S,
+    resource: &Resource,
+    id: &str,
+    image: &[u8],
+) -> ServiceResponse<B>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    upload_with(app, resource, id, image, &[]).await
+}
+
+/// Signs a crop sent as `field` along with `image`.
+pub fn sign_crop(resource: &Resource, id: &str, image: &[u8], field: &str, value: &str) -> String {
+    let hash = hex::encode(openssl::sha::sha1(image));
+
+    sign(format!("crop:{resource}:{id}:{hash}:{field}={value}").as_bytes())
+}
+
+/// Uploads `image` along with additional multipart `fields`, signing any crop among them.
+pub async fn upload_with<S, B>(
+    app: &S,
+    resource: &Resource,
+    id: &str,
+    image: &[u8],
+    fields: &[(&str, &str)],
+) -> ServiceResponse<B>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let crop_signatures: Vec<String> = fields
+        .iter()
+        .filter(|(name, _)| matches!(*name, "focal_point" | "crop"))
+        .map(|(name, value)| sign_crop(resource, id, image, name, value))
+        .collect();
+    let fields: Vec<(&str, &str)> = fields
+        .iter()
+        .copied()
+        .chain(
+            crop_signatures
+                .iter()
+                .map(|signature| ("crop_signature", signature.as_str())),
+        )
+        .collect();
+
+    upload_multipart(app, resource, id, multipart(image, &sign(image), &fields)).await
+}
+
+/// Uploads a multipart `body` as it is.
+pub async fn upload_multipart<S, B>(
+    app: &S,
+    resource: &Resource,
+    id: &str,
+    body: Vec<u8>,
+) -> ServiceResponse<B>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let request = test::TestRequest::post()
+        .uri(&format!("/{}/{id}", resource.route()))
+        .insert_header((
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        ))
+        .set_payload(body)
+        .to_request();
+
+    test::call_service(app, request).await
+}
+
+pub async fn upload_filename<S, B>(app: &S, resource: &Resource, id: &str, image: &[u8]) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = upload(app, resource, id, image).await;
+    assert_eq!(
+        response.status(),
+        StatusCode::CREATED,
+        "upload to {resource}"
+    );
+
+    let body: Value = test::read_body_json(response).await;
+    body["filename"].as_str().unwrap().to_string()
+}
+
+/// An animated GIF of two 64x64 frames.
+pub fn animated_gif() -> Vec<u8> {
+    let mut data = Vec::new();
+
+    {
+        let mut encoder = GifEncoder::new(&mut data);
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+
+        for color in FRAME_COLORS {
+            encoder
+                .encode_frame(Frame::from_parts(
+                    RgbaImage::from_pixel(64, 64, Rgba(color)),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                ))
+                .unwrap();
+        }
+    }
+
+    data
+}
+
+/// The colors of the frames of the test animations.
+const FRAME_COLORS: [[u8; 4]; 2] = [[255, 0, 0, 255], [0, 0, 255, 255]];
+
+/// An APNG of two 64x64 frames.
+pub fn animated_png() -> Vec<u8> {
+    let mut data = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(&mut data, 64, 64);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 0).unwrap();
+        encoder.set_frame_delay(1, 10).unwrap();
+
+        let mut writer = encoder.write_header().unwrap();
+
+        for color in FRAME_COLORS {
+            let frame = RgbaImage::from_pixel(64, 64, Rgba(color));
+            writer.write_image_data(frame.as_raw()).unwrap();
+        }
+
+        writer.finish().unwrap();
+    }
+
+    data
+}
+
+/// An animated WebP of two 64x64 frames.
+pub fn animated_webp() -> Vec<u8> {
+    let frames = FRAME_COLORS.map(|color| RgbaImage::from_pixel(64, 64, Rgba(color)));
+    let config = webp::WebPConfig::new().unwrap();
+    let mut encoder = webp::AnimEncoder::new(64, 64, &config);
+
+    for (index, frame) in frames.iter().enumerate() {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.as_raw(),
+            64,
+            64,
+            index as i32 * 100,
+        ));
+    }
+
+    encoder.encode().to_vec()
+}
+
+pub const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+pub const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+pub const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+/// A 300x100 PNG of three squares: red, green and blue from left to right.
+///
+/// With `detailed`, the blue square is a checkerboard of blue and white instead.
+pub fn stripes(detailed: bool) -> Vec<u8> {
+    let image = RgbaImage::from_fn(300, 100, |x, y| match x / 100 {
+        0 => RED,
+        1 => GREEN,
+        _ if detailed && (x / 5 + y / 5) % 2 == 0 => Rgba([255, 255, 255, 255]),
+        _ => BLUE,
+    });
+
+    let mut data = Vec::new();
+    DynamicImage::ImageRgba8(image)
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+        .unwrap();
+
+    data
+}
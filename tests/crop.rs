@@ -0,0 +1,209 @@
+mod common;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test,
+};
+use image::{DynamicImage, GenericImageView};
+use rs_cdn::{
+    config::{CdnConfig, CropMode, ResourceConfig},
+    rest::Resource,
+};
+use serde_json::Value;
+use tempfile::TempDir;
+
+use common::*;
+
+fn resource(name: &str, crop: CropMode) -> ResourceConfig {
+    ResourceConfig {
+        crop,
+        ..ResourceConfig::new(name)
+    }
+}
+
+fn config() -> CdnConfig {
+    CdnConfig {
+        resources: vec![
+            resource("center", CropMode::Center),
+            resource("entropy", CropMode::Entropy),
+            resource("contain", CropMode::Contain),
+            ResourceConfig {
+                singleton: false,
+                ..resource("gallery", CropMode::Center)
+            },
+        ],
+        ..CdnConfig::default()
+    }
+}
+
+/// Uploads `image` and returns the rendition at size 128, and the crop recorded for it.
+async fn upload_and_crop<S, B>(
+    app: &S,
+    resource: &str,
+    id: &str,
+    image: &[u8],
+    fields: &[(&str, &str)],
+) -> (DynamicImage, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let resource = Resource::new(ResourceConfig::new(resource));
+    let response = upload_with(app, &resource, id, image, fields).await;
+    assert_eq!(response.status(), StatusCode::CREATED, "{fields:?}");
+
+    let body: Value = test::read_body_json(response).await;
+    let filename = body["filename"].as_str().unwrap();
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/{resource}/{id}/{filename}?size=128"))
+        .to_request();
+    let body = test::call_and_read_body(app, request).await;
+    let image = image::load_from_memory(&body).unwrap();
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/{resource}/{id}"))
+        .to_request();
+    let items: Value = test::call_and_read_body_json(app, request).await;
+
+    (image, items["items"][0]["crop"].clone())
+}
+
+#[actix_web::test]
+async fn uploads_are_cropped_by_the_mode_of_their_resource() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+
+    let (image, crop) = upload_and_crop(&app, "center", "1", &stripes(false), &[]).await;
+    assert_eq!(image.get_pixel(64, 64), GREEN);
+    assert_eq!(crop["kind"], "center");
+    assert_eq!(crop["x"], 100);
+
+    let (image, crop) = upload_and_crop(&app, "entropy", "1", &stripes(true), &[]).await;
+    assert_ne!(image.get_pixel(64, 64), GREEN);
+    assert_eq!(crop["kind"], "entropy");
+    assert!(crop["x"].as_u64().unwrap() > 150, "{crop}");
+
+    let (image, crop) = upload_and_crop(&app, "contain", "1", &stripes(false), &[]).await;
+    assert_eq!(image.dimensions(), (128, 128));
+    assert_eq!(image.get_pixel(64, 5).0[3], 0, "padding is transparent");
+    assert_eq!(image.get_pixel(64, 64), GREEN);
+    assert_eq!(image.get_pixel(5, 64), RED);
+    assert_eq!(crop["kind"], "contain");
+    assert_eq!(crop["width"], 300);
+}
+
+#[actix_web::test]
+async fn uploads_are_cropped_as_requested() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+
+    let fields = [("focal_point", "10,50")];
+    let (image, crop) = upload_and_crop(&app, "center", "1", &stripes(false), &fields).await;
+    assert_eq!(image.get_pixel(64, 64), RED);
+    assert_eq!(crop["kind"], "focal_point");
+    assert_eq!(crop["x"], 0);
+
+    let fields = [("crop", "200,0,100,100")];
+    let (image, crop) = upload_and_crop(&app, "center", "2", &stripes(false), &fields).await;
+    assert_eq!(image.get_pixel(64, 64), BLUE);
+    assert_eq!(crop["kind"], "rectangle");
+
+    let center = Resource::new(ResourceConfig::new("center"));
+
+    for fields in [
+        [("crop", "0,0,100,50")],
+        [("crop", "250,0,100,100")],
+        [("crop", "0,0,100")],
+        [("focal_point", "300,0")],
+        [("focal_point", "left")],
+    ] {
+        let response = upload_with(&app, &center, "3", &stripes(false), &fields).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{fields:?}");
+    }
+}
+
+#[actix_web::test]
+async fn requested_crops_have_to_be_signed() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let center = Resource::new(ResourceConfig::new("center"));
+    let image = stripes(false);
+    let signature = sign(&image);
+    let crop_signature = sign_crop(&center, "1", &image, "focal_point", "10,50");
+
+    for (fields, status) in [
+        (vec![("focal_point", "10,50")], StatusCode::BAD_REQUEST),
+        // Signed for another focal point, or another id
+        (
+            vec![
+                ("focal_point", "290,50"),
+                ("crop_signature", &crop_signature),
+            ],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            vec![("focal_point", "10,50"), ("crop_signature", &signature)],
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            vec![
+                ("focal_point", "10,50"),
+                ("crop_signature", &crop_signature),
+            ],
+            StatusCode::CREATED,
+        ),
+    ] {
+        let body = multipart(&image, &signature, &fields);
+        let response = upload_multipart(&app, &center, "1", body).await;
+
+        assert_eq!(response.status(), status, "{fields:?}");
+    }
+}
+
+#[actix_web::test]
+async fn long_strips_are_rejected_or_measured_quickly() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+
+    let strip = |width: u32, height: u32| {
+        let mut data = Vec::new();
+        DynamicImage::new_rgba8(width, height)
+            .write_to(
+                &mut std::io::Cursor::new(&mut data),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        data
+    };
+
+    for mode in ["center", "entropy", "contain"] {
+        let resource = Resource::new(ResourceConfig::new(mode));
+
+        let response = upload(&app, &resource, "1", &strip(1, 65535)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{mode}");
+
+        let started_at = std::time::Instant::now();
+        let response = upload(&app, &resource, "2", &strip(8, 256)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{mode}");
+        assert!(started_at.elapsed().as_secs() < 5, "{mode}");
+    }
+}
+
+#[actix_web::test]
+async fn recropped_items_are_purged_from_the_cache() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+
+    let fields = [("focal_point", "10,50")];
+    let (image, _) = upload_and_crop(&app, "gallery", "1", &stripes(false), &fields).await;
+    assert_eq!(image.get_pixel(64, 64), RED);
+
+    // The same image again, which replaces the item instead of adding another one
+    let fields = [("focal_point", "290,50")];
+    let (image, crop) = upload_and_crop(&app, "gallery", "1", &stripes(false), &fields).await;
+    assert_eq!(image.get_pixel(64, 64), BLUE);
+    assert_eq!(crop["x"], 200);
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use image::{GenericImageView, ImageFormat};
+use rs_cdn::{
+    config::{CdnConfig, OutputFormat, QualityConfig, ResourceConfig},
+    rest::Resource,
+};
+use tempfile::TempDir;
+
+use common::*;
+
+const BROWSER_ACCEPT: &str = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+
+fn icons() -> ResourceConfig {
+    ResourceConfig {
+        output_formats: vec![OutputFormat::Webp],
+        quality: QualityConfig {
+            webp_lossless: true,
+            ..QualityConfig::default()
+        },
+        ..ResourceConfig::new("icons")
+    }
+}
+
+fn config() -> CdnConfig {
+    CdnConfig {
+        resources: vec![ResourceConfig::new("avatars"), icons()],
+        ..CdnConfig::default()
+    }
+}
+
+fn is_avif(body: &[u8]) -> bool {
+    body.get(4..12) == Some(b"ftypavif".as_slice())
+}
+
+#[actix_web::test]
+async fn formats_are_chosen_by_extension() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", ORANGE).await;
+    let hash = filename.trim_end_matches(".png");
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{hash}.webp?size=128"))
+        .insert_header((header::ACCEPT, "image/avif"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/webp"
+    );
+    assert!(response.headers().get(header::VARY).is_none());
+
+    let body = test::read_body(response).await;
+    let image = image::load_from_memory_with_format(&body, ImageFormat::WebP).unwrap();
+    assert_eq!(image.dimensions(), (128, 128));
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{hash}.avif?size=128"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/avif"
+    );
+    assert!(is_avif(&test::read_body(response).await));
+
+    // Icons aren't served as AVIF
+    let filename = upload_filename(&app, &Resource::new(icons()), "1", ORANGE).await;
+    let hash = filename.trim_end_matches(".png");
+    let request = test::TestRequest::get()
+        .uri(&format!("/icons/1/{hash}.avif?size=128"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn png_requests_are_negotiated() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let avatars = resource("avatars");
+    let icons = Resource::new(icons());
+
+    for (resource, accept, content_type) in [
+        (&avatars, None, "image/png"),
+        (&avatars, Some(BROWSER_ACCEPT), "image/avif"),
+        (&avatars, Some("image/webp,*/*"), "image/webp"),
+        (&avatars, Some("image/avif;q=0,image/webp"), "image/webp"),
+        (&avatars, Some("text/html"), "image/png"),
+        (&icons, Some(BROWSER_ACCEPT), "image/webp"),
+    ] {
+        let filename = upload_filename(&app, resource, "negotiated", ORANGE).await;
+        let mut request =
+            test::TestRequest::get().uri(&format!("/{resource}/negotiated/{filename}?size=128"));
+
+        if let Some(accept) = accept {
+            request = request.insert_header((header::ACCEPT, accept));
+        }
+
+        let response = test::call_service(&app, request.to_request()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            content_type,
+            "{resource} requested with {accept:?}"
+        );
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
+    }
+}
+
+#[actix_web::test]
+async fn formats_are_cached_separately() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, config()).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", ORANGE).await;
+    let hash = filename.trim_end_matches(".png");
+
+    let request = |path: String, accept: &str| {
+        test::TestRequest::get()
+            .uri(&path)
+            .insert_header((header::ACCEPT, accept.to_string()))
+            .to_request()
+    };
+    let png = format!("/avatars/1/{filename}?size=128");
+    let webp = format!("/avatars/1/{hash}.webp?size=128");
+
+    for (path, accept, cached) in [
+        (png.clone(), "image/webp", false),
+        (webp, "*/*", true),
+        (png.clone(), "image/png", false),
+        (png, "image/png", true),
+    ] {
+        let response = test::call_service(&app, request(path.clone(), accept)).await;
+
+        assert_eq!(
+            response.headers().get("X-Origin-Status").unwrap() != "origin",
+            cached,
+            "{path} requested with {accept}"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use std::{
+    fs,
+    sync::{mpsc, Arc},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test,
+};
+use rs_cdn::{
+    cache::memory::MemoryCache,
+    config::{CdnConfig, ImagePoolConfig, StorageConfig},
+};
+use serde_json::Value;
+use tempfile::TempDir;
+use tokio::sync::Mutex;
+
+use common::*;
+
+/// Held by every test, as the signing key is read from the environment they share.
+static ENV: Mutex<()> = Mutex::const_new(());
+
+async fn get_json<S, B>(app: &S, path: &str) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let request = test::TestRequest::get().uri(path).to_request();
+    let response = test::call_service(app, request).await;
+    let status = response.status();
+
+    (status, test::read_body_json(response).await)
+}
+
+/// Asserts that the service isn't ready, and that `check` is the reason.
+fn assert_unready(status: StatusCode, body: &Value, check: &str) {
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{body}");
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["checks"][check]["ok"], false, "{body}");
+    assert!(body["checks"][check]["error"].is_string(), "{body}");
+    assert_eq!(body["checks"]["cache"]["ok"], true);
+}
+
+fn cdn_config() -> CdnConfig {
+    CdnConfig {
+        storage: StorageConfig {
+            min_free_space: 0,
+            ..StorageConfig::default()
+        },
+        ..CdnConfig::default()
+    }
+}
+
+#[actix_web::test]
+async fn ready_services_pass_every_check() {
+    let _env = ENV.lock().await;
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, cdn_config()).await;
+
+    let (status, body) = get_json(&app, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+
+    let (status, body) = get_json(&app, "/readyz").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["status"], "ok");
+
+    for check in [
+        "cache",
+        "storage",
+        "disk_space",
+        "signing_key",
+        "image_pool",
+    ] {
+        assert_eq!(body["checks"][check]["ok"], true, "{check}");
+    }
+}
+
+#[actix_web::test]
+async fn unwritable_storage_is_not_ready() {
+    let _env = ENV.lock().await;
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, cdn_config()).await;
+
+    // Nothing can be written beneath a file, not even by root
+    fs::write(storage_dir.path().join("files"), b"").unwrap();
+
+    let (status, body) = get_json(&app, "/readyz").await;
+    assert_unready(status, &body, "storage");
+
+    // Liveness doesn't depend on storage
+    assert_eq!(get_json(&app, "/healthz").await.0, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn low_disk_space_is_not_ready() {
+    let _env = ENV.lock().await;
+    let storage_dir = TempDir::new().unwrap();
+    let config = CdnConfig {
+        storage: StorageConfig {
+            min_free_space: u64::MAX,
+            ..StorageConfig::default()
+        },
+        ..CdnConfig::default()
+    };
+    let app = app(&storage_dir, config).await;
+
+    let (status, body) = get_json(&app, "/readyz").await;
+    assert_unready(status, &body, "disk_space");
+    assert_eq!(body["checks"]["storage"]["ok"], true);
+}
+
+#[actix_web::test]
+async fn missing_signing_keys_are_not_ready() {
+    let _env = ENV.lock().await;
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, cdn_config()).await;
+
+    std::env::set_var(
+        "PUBLIC_KEY_PATH",
+        storage_dir.path().join("missing.pub").to_str().unwrap(),
+    );
+
+    let (status, body) = get_json(&app, "/readyz").await;
+    assert_unready(status, &body, "signing_key");
+}
+
+#[actix_web::test]
+async fn saturated_image_pools_are_not_ready() {
+    let _env = ENV.lock().await;
+    let storage_dir = TempDir::new().unwrap();
+    let config = CdnConfig {
+        image_pool: ImagePoolConfig {
+            concurrency: 1,
+            queue_limit: 0,
+            ..ImagePoolConfig::default()
+        },
+        ..cdn_config()
+    };
+    let cdn = cdn(
+        &storage_dir,
+        config,
+        Arc::new(MemoryCache::new(64 * 1024 * 1024)),
+    );
+    let app = app_with_cdn(cdn.clone()).await;
+
+    // Keeps the only slot of the pool busy
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let pool = cdn.pool.clone();
+    let running = actix_web::rt::spawn(async move {
+        pool.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        })
+        .await
+    });
+    actix_web::rt::task::spawn_blocking(move || started_rx.recv().unwrap())
+        .await
+        .unwrap();
+
+    let (status, body) = get_json(&app, "/readyz").await;
+    assert_unready(status, &body, "image_pool");
+
+    release_tx.send(()).unwrap();
+    running.await.unwrap().unwrap();
+
+    assert_eq!(get_json(&app, "/readyz").await.0, StatusCode::OK);
+}
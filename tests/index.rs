@@ -0,0 +1,93 @@
+use rs_cdn::index::{MetadataIndex, ResourceMetadata};
+use tempfile::TempDir;
+
+fn metadata(id: &str, hash: &str) -> ResourceMetadata {
+    ResourceMetadata {
+        resource: "avatars".to_string(),
+        id: id.to_string(),
+        hash: hash.to_string(),
+        filename: format!("{hash}.png"),
+        format: "png".to_string(),
+        width: 1,
+        height: 1,
+        animated: false,
+        frame_count: 1,
+        size: 1,
+        uploaded_at: 0,
+        files: vec![format!("{hash}.png")],
+        crop: None,
+    }
+}
+
+fn open(dir: &TempDir) -> MetadataIndex {
+    MetadataIndex::open(dir.path().to_str().unwrap()).unwrap()
+}
+
+fn hashes(items: Vec<ResourceMetadata>) -> Vec<String> {
+    items.into_iter().map(|item| item.hash).collect()
+}
+
+#[test]
+fn inserted_metadata_is_looked_up_by_id_and_hash() {
+    let dir = TempDir::new().unwrap();
+    let index = open(&dir);
+
+    for (id, hash) in [("1", "b"), ("1", "a"), ("10", "c")] {
+        index.insert(&metadata(id, hash)).unwrap();
+    }
+
+    let item = index.get("avatars", "1", "a").unwrap().unwrap();
+    assert_eq!(item.filename, "a.png");
+    assert!(index.get("avatars", "1", "c").unwrap().is_none());
+    assert!(index.get("banners", "1", "a").unwrap().is_none());
+
+    // Ids sharing a prefix are listed apart
+    assert_eq!(hashes(index.list("avatars", "1").unwrap()), ["a", "b"]);
+    assert_eq!(hashes(index.list("avatars", "10").unwrap()), ["c"]);
+    assert_eq!(index.all().unwrap().len(), 3);
+
+    // Inserting the same hash again replaces its metadata
+    index
+        .insert(&ResourceMetadata {
+            width: 2,
+            ..metadata("1", "a")
+        })
+        .unwrap();
+    assert_eq!(index.get("avatars", "1", "a").unwrap().unwrap().width, 2);
+    assert_eq!(index.all().unwrap().len(), 3);
+}
+
+#[test]
+fn removed_metadata_is_gone() {
+    let dir = TempDir::new().unwrap();
+    let index = open(&dir);
+
+    index.insert(&metadata("1", "a")).unwrap();
+    index.insert(&metadata("1", "b")).unwrap();
+    index.remove("avatars", "1", "a").unwrap();
+
+    assert!(index.get("avatars", "1", "a").unwrap().is_none());
+    assert_eq!(hashes(index.list("avatars", "1").unwrap()), ["b"]);
+
+    // Removing metadata that doesn't exist is fine
+    index.remove("avatars", "1", "a").unwrap();
+}
+
+#[test]
+fn reopened_indexes_keep_their_metadata() {
+    let dir = TempDir::new().unwrap();
+
+    {
+        let index = open(&dir);
+
+        index.insert(&metadata("1", "a")).unwrap();
+        index.insert(&metadata("1", "b")).unwrap();
+        index.remove("avatars", "1", "b").unwrap();
+        index.mark_items_migrated().unwrap();
+    }
+
+    let index = open(&dir);
+
+    assert_eq!(hashes(index.list("avatars", "1").unwrap()), ["a"]);
+    assert!(index.items_migrated().unwrap());
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use rs_cdn::cache::{memory::MemoryCache, CacheBackend};
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn value(size: usize) -> Bytes {
+    Bytes::from(vec![0; size])
+}
+
+#[actix_web::test]
+async fn evicted_keys_are_removed_from_their_index() {
+    let cache = MemoryCache::new(100);
+
+    for n in 0..10 {
+        let key = format!("key-{n}");
+
+        cache.put(&key, value(50), TTL).await.unwrap();
+        cache
+            .add_to_index(&format!("index-{n}"), &key, TTL)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(cache.indexed_keys(), 2);
+
+    // Values that don't fit aren't cached, and so aren't indexed
+    cache.put("too-large", value(101), TTL).await.unwrap();
+    cache.add_to_index("index", "too-large", TTL).await.unwrap();
+
+    assert_eq!(cache.indexed_keys(), 2);
+}
+
+#[actix_web::test]
+async fn least_recently_used_values_are_evicted() {
+    let cache = MemoryCache::new(100);
+
+    cache.put("a", value(40), TTL).await.unwrap();
+    cache.put("b", value(40), TTL).await.unwrap();
+
+    // Reading "a" makes "b" the least recently used value
+    assert!(cache.get("a").await.unwrap().is_some());
+
+    cache.put("c", value(40), TTL).await.unwrap();
+
+    assert!(cache.get("a").await.unwrap().is_some());
+    assert!(cache.get("b").await.unwrap().is_none());
+    assert!(cache.get("c").await.unwrap().is_some());
+}
+
+#[actix_web::test]
+async fn sizes_are_accounted_for_in_bytes() {
+    let cache = MemoryCache::new(2 * 1024 * 1024);
+
+    cache.put("a", value(1024 * 1024), TTL).await.unwrap();
+    cache.put("b", value(512 * 1024), TTL).await.unwrap();
+
+    let health = cache.health().await.unwrap();
+    assert_eq!(health.memory_usage, "1.50M");
+    assert_eq!(health.num_keys, 2);
+
+    // Replacing a value only counts the new one
+    cache.put("a", value(256 * 1024), TTL).await.unwrap();
+    assert_eq!(cache.health().await.unwrap().memory_usage, "0.75M");
+
+    // Values larger than the whole cache are dropped without evicting anything
+    cache.put("c", value(3 * 1024 * 1024), TTL).await.unwrap();
+
+    let health = cache.health().await.unwrap();
+    assert_eq!(health.memory_usage, "0.75M");
+    assert_eq!(health.num_keys, 2);
+}
+
+#[actix_web::test]
+async fn expired_values_are_dropped() {
+    let cache = MemoryCache::new(100);
+
+    cache
+        .put("short", value(10), Duration::from_millis(50))
+        .await
+        .unwrap();
+    cache.put("long", value(10), TTL).await.unwrap();
+    cache.add_to_index("index", "short", TTL).await.unwrap();
+
+    assert!(cache.get("short").await.unwrap().is_some());
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+
+    assert!(cache.get("short").await.unwrap().is_none());
+    assert!(cache.get("long").await.unwrap().is_some());
+
+    let health = cache.health().await.unwrap();
+    assert_eq!(health.num_keys, 1);
+    assert_eq!(cache.indexed_keys(), 0);
+}
+
+#[actix_web::test]
+async fn purging_an_index_removes_its_values() {
+    let cache = MemoryCache::new(100);
+
+    for (index, key) in [("1", "1-small"), ("1", "1-large"), ("2", "2-small")] {
+        cache.put(key, value(10), TTL).await.unwrap();
+        cache.add_to_index(index, key, TTL).await.unwrap();
+    }
+
+    cache.purge_index("1").await.unwrap();
+
+    assert!(cache.get("1-small").await.unwrap().is_none());
+    assert!(cache.get("1-large").await.unwrap().is_none());
+    assert!(cache.get("2-small").await.unwrap().is_some());
+    assert_eq!(cache.health().await.unwrap().memory_usage, "0.00M");
+    assert_eq!(cache.indexed_keys(), 1);
+
+    // Purging an index that doesn't exist is fine
+    cache.purge_index("3").await.unwrap();
+}
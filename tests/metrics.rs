@@ -0,0 +1,95 @@
+mod common;
+
+use actix_web::{http::StatusCode, test};
+use rs_cdn::config::CdnConfig;
+use tempfile::TempDir;
+
+use common::*;
+
+/// Returns the value of the first sample of `metric` carrying all `labels`.
+fn sample(metrics: &str, metric: &str, labels: &[&str]) -> Option<f64> {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter(|line| {
+            line.strip_prefix(metric)
+                .is_some_and(|rest| rest.starts_with(['{', ' ']))
+        })
+        .find(|line| labels.iter().all(|label| line.contains(label)))
+        .and_then(|line| line.rsplit(' ').next()?.parse().ok())
+}
+
+#[actix_web::test]
+async fn requests_are_recorded_in_the_metrics() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let filename = upload_filename(&app, &resource("avatars"), "1", ORANGE).await;
+
+    for _ in 0..2 {
+        let request = test::TestRequest::get()
+            .uri(&format!("/avatars/1/{filename}?size=128"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    let request = test::TestRequest::get().uri("/metrics").to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .headers()
+        .get("Content-Type")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+
+    let metrics = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    // Requests are labelled by their route rather than their path
+    let uploads = ["resource=\"avatars\"", "status=\"201\""];
+    assert_eq!(
+        sample(&metrics, "rs_cdn_http_requests_total", &uploads),
+        Some(1.0)
+    );
+    assert!(!metrics.contains(&filename), "{metrics}");
+
+    let renders = ["resource=\"avatars\"", "status=\"200\""];
+    assert_eq!(
+        sample(&metrics, "rs_cdn_http_requests_total", &renders),
+        Some(2.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            "rs_cdn_http_request_duration_seconds_count",
+            &renders
+        ),
+        Some(2.0)
+    );
+    assert!(sample(
+        &metrics,
+        "rs_cdn_http_request_duration_seconds_bucket",
+        &["resource=\"avatars\"", "le=\"+Inf\""]
+    )
+    .is_some());
+
+    assert_eq!(
+        sample(&metrics, "rs_cdn_upload_bytes_count", &[]),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&metrics, "rs_cdn_render_duration_seconds_count", &[]),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            "rs_cdn_cache_lookups_total",
+            &["tier=\"memory\"", "result=\"hit\""]
+        ),
+        Some(1.0)
+    );
+    assert!(sample(&metrics, "rs_cdn_storage_bytes_written_total", &[]).unwrap() > 0.0);
+}
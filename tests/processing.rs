@@ -0,0 +1,61 @@
+use std::sync::mpsc;
+
+use actix_web::{
+    http::{header, StatusCode},
+    ResponseError,
+};
+use rs_cdn::{access_log, config::ImagePoolConfig, processing::ImagePool};
+
+#[actix_web::test]
+async fn tasks_keep_the_request_id() {
+    let pool = ImagePool::new(&ImagePoolConfig::default());
+
+    let request_id = access_log::scope("request-1".to_string(), async {
+        pool.run(access_log::current_request_id).await.unwrap()
+    })
+    .await;
+    assert_eq!(request_id.as_deref(), Some("request-1"));
+
+    let request_id = pool.run(access_log::current_request_id).await.unwrap();
+    assert_eq!(request_id, None);
+}
+
+#[actix_web::test]
+async fn saturated_pools_reject_tasks_with_a_retry_after() {
+    let pool = ImagePool::new(&ImagePoolConfig {
+        concurrency: 1,
+        queue_limit: 0,
+        retry_after: 7,
+    });
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+
+    let running = actix_web::rt::spawn({
+        let pool = pool.clone();
+
+        async move {
+            pool.run(move || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            })
+            .await
+        }
+    });
+    actix_web::rt::task::spawn_blocking(move || started_rx.recv().unwrap())
+        .await
+        .unwrap();
+
+    assert!(pool.is_saturated());
+
+    let error = pool.run(|| ()).await.unwrap_err();
+    let response = error.error_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "7");
+
+    release_tx.send(()).unwrap();
+    running.await.unwrap().unwrap();
+
+    // The slot is free again once the task finished
+    assert!(!pool.is_saturated());
+    pool.run(|| ()).await.unwrap();
+}
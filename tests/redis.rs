@@ -0,0 +1,69 @@
+//! Runs against a Redis server, and is skipped unless `REDIS_TEST_URL` is set, e.g.:
+//!
+//! ```sh
+//! docker run -d -p 6379:6379 redis
+//! REDIS_TEST_URL=redis://127.0.0.1 cargo test --test redis
+//! ```
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use rs_cdn::{
+    breaker::BreakerState,
+    cache::{redis::RedisCache, CacheBackend},
+    config::RedisConfig,
+    redis_pool::RedisPool,
+};
+
+const TTL: Duration = Duration::from_secs(60);
+
+async fn cache() -> Option<RedisCache> {
+    let Ok(url) = env::var("REDIS_TEST_URL") else {
+        eprintln!("REDIS_TEST_URL is not set, skipping");
+        return None;
+    };
+
+    let pool = RedisPool::new(&url, &RedisConfig::default()).unwrap();
+    let started_at = Instant::now();
+
+    pool.connect_in_background();
+
+    while pool.connection().is_none() {
+        assert!(
+            started_at.elapsed() < Duration::from_secs(5),
+            "Could not connect to {url}"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    Some(RedisCache::new(pool))
+}
+
+/// Keys no other test run uses.
+fn keys() -> (String, String) {
+    let prefix = format!("rs-cdn-test:{}", uuid::Uuid::new_v4());
+
+    (format!("{prefix}:keys"), format!("{prefix}:key"))
+}
+
+#[actix_web::test]
+async fn purging_ids_without_renditions_succeeds() {
+    let Some(cache) = cache().await else {
+        return;
+    };
+    let (index, key) = keys();
+
+    cache.purge_index(&index).await.unwrap();
+    assert_eq!(cache.breaker_state(), Some(BreakerState::Closed));
+
+    cache
+        .put(&key, Bytes::from_static(b"rendition"), TTL)
+        .await
+        .unwrap();
+    cache.add_to_index(&index, &key, TTL).await.unwrap();
+    cache.purge_index(&index).await.unwrap();
+
+    assert_eq!(cache.get(&key).await.unwrap(), None);
+}
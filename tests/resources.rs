@@ -0,0 +1,384 @@
+mod common;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use image::{GenericImageView, ImageFormat};
+use rs_cdn::{
+    config::{CdnConfig, ResourceConfig},
+    rest::Resource,
+};
+use serde_json::Value;
+use tempfile::TempDir;
+
+use common::*;
+
+#[actix_web::test]
+async fn uploads_are_read_back_through_their_own_resource() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+
+    for resource in resources() {
+        let id = format!("{resource}-owner");
+        let filename = upload_filename(&app, &resource, &id, ORANGE).await;
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/{id}/{filename}?size=128", resource.route()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::OK, "read from {resource}");
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+
+        let body = test::read_body(response).await;
+        let image = image::load_from_memory_with_format(&body, ImageFormat::Png).unwrap();
+        assert_eq!(image.dimensions(), (128, 128));
+
+        // Hashes are stored in lowercase, which is the only way they are served
+        let (hash, ext) = filename.split_once('.').unwrap();
+        let request = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/{id}/{}.{ext}?size=128",
+                resource.route(),
+                hash.to_uppercase()
+            ))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/{id}/versions", resource.route()))
+            .to_request();
+        let versions: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(versions["versions"][0]["filename"], filename.as_str());
+
+        for other in resources().into_iter().filter(|other| *other != resource) {
+            let request = test::TestRequest::get()
+                .uri(&format!("/{}/{id}/{filename}?size=128", other.route()))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_FOUND,
+                "{resource} upload read from {other}"
+            );
+        }
+    }
+}
+
+#[actix_web::test]
+async fn animations_are_kept_only_by_animated_resources() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let gif = animated_gif();
+
+    for resource in resources() {
+        let id = format!("{resource}-animated");
+        let filename = upload_filename(&app, &resource, &id, &gif).await;
+
+        assert_eq!(
+            filename.starts_with("a_"),
+            resource.animated(),
+            "animated upload to {resource} stored as {filename}"
+        );
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/{id}/{filename}?size=128", resource.route()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK, "read from {resource}");
+
+        if resource.animated() {
+            let animation = filename.replace(".png", ".gif");
+            let request = test::TestRequest::get()
+                .uri(&format!("/{}/{id}/{animation}?size=128", resource.route()))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                "image/gif"
+            );
+        }
+    }
+}
+
+#[actix_web::test]
+async fn uploads_are_checked_against_the_rules_of_their_resource() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+
+    for resource in resources() {
+        let response = upload(&app, &resource, "not-an-image", b"plain text, not an image").await;
+        assert_eq!(
+            response.status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "text upload to {resource}"
+        );
+
+        let oversized = vec![0; resource.max_upload_size() + 1];
+        let response = upload(&app, &resource, "oversized", &oversized).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "oversized upload to {resource}"
+        );
+    }
+
+    // Fits the limit of avatars, but not the one of icons
+    let (avatars, icons) = (resource("avatars"), resource("icons"));
+    let size = (icons.max_upload_size() + avatars.max_upload_size()) / 2;
+    let mut image = ORANGE.to_vec();
+    image.resize(size, 0);
+
+    let response = upload(&app, &icons, "large", &image).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let response = upload(&app, &avatars, "large", &image).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn resources_are_served_as_configured() {
+    let storage_dir = TempDir::new().unwrap();
+    let banners = ResourceConfig {
+        route: Some("banner-images".to_string()),
+        sizes: vec![64, 600],
+        default_size: 600,
+        ..ResourceConfig::new("banners")
+    };
+    let config = CdnConfig {
+        resources: vec![banners.clone()],
+        ..CdnConfig::default()
+    };
+    let app = app(&storage_dir, config).await;
+    let banners = Resource::new(banners);
+
+    let filename = upload_filename(&app, &banners, "1", ORANGE).await;
+
+    for (query, status, size) in [
+        ("", StatusCode::OK, Some(600)),
+        ("?size=64", StatusCode::OK, Some(64)),
+        ("?size=128", StatusCode::BAD_REQUEST, None),
+    ] {
+        let request = test::TestRequest::get()
+            .uri(&format!("/banner-images/1/{filename}{query}"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), status, "banner requested with {query:?}");
+
+        if let Some(size) = size {
+            let body = test::read_body(response).await;
+            let image = image::load_from_memory(&body).unwrap();
+            assert_eq!(image.dimensions(), (size, size));
+        }
+    }
+
+    // Resources that aren't configured aren't served
+    let request = test::TestRequest::get()
+        .uri(&format!("/avatars/1/{filename}"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn items_of_non_singleton_resources_are_listed_and_deleted() {
+    let storage_dir = TempDir::new().unwrap();
+    let attachments = ResourceConfig {
+        singleton: false,
+        max_items: Some(3),
+        ..ResourceConfig::new("attachments")
+    };
+    let config = CdnConfig {
+        resources: vec![attachments.clone(), ResourceConfig::new("avatars")],
+        ..CdnConfig::default()
+    };
+    let app = app(&storage_dir, config).await;
+    let attachments = Resource::new(attachments);
+
+    let images: [&[u8]; 3] = [
+        ORANGE,
+        include_bytes!("../assets/plane.jpg"),
+        include_bytes!("../assets/sample.png"),
+    ];
+    let mut filenames = Vec::new();
+
+    for image in images {
+        filenames.push(upload_filename(&app, &attachments, "1", image).await);
+    }
+
+    // Every item is kept, unlike with singletons
+    for filename in &filenames {
+        let request = test::TestRequest::get()
+            .uri(&format!("/attachments/1/{filename}?size=128"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    // The cap applies to new items only
+    let response = upload(&app, &attachments, "1", &animated_gif()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    upload_filename(&app, &attachments, "1", ORANGE).await;
+
+    let request = test::TestRequest::get()
+        .uri("/attachments/1?limit=2")
+        .to_request();
+    let page: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(page["items"].as_array().unwrap().len(), 2);
+
+    let next = page["next"].as_str().unwrap();
+    let request = test::TestRequest::get()
+        .uri(&format!("/attachments/1?limit=2&after={next}"))
+        .to_request();
+    let last_page: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(last_page["items"].as_array().unwrap().len(), 1);
+    assert!(last_page["next"].is_null());
+
+    let mut hashes: Vec<&str> = page["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .chain(last_page["items"].as_array().unwrap())
+        .map(|item| item["hash"].as_str().unwrap())
+        .collect();
+    hashes.sort();
+    hashes.dedup();
+    assert_eq!(hashes.len(), 3);
+
+    let hash = filenames[0].trim_end_matches(".png");
+    let now = now();
+    let delete = |message: &str, timestamp: u64| {
+        test::TestRequest::delete()
+            .uri(&format!("/attachments/1/{hash}"))
+            .insert_header(("X-Signature", sign(message.as_bytes())))
+            .insert_header(("X-Signature-Timestamp", timestamp.to_string()))
+            .to_request()
+    };
+
+    let message = format!("delete:attachments:1:other:{now}");
+    let response = test::call_service(&app, delete(&message, now)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Replayed long after it was signed
+    let stale = now - 3600;
+    let message = format!("delete:attachments:1:{hash}:{stale}");
+    let response = test::call_service(&app, delete(&message, stale)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let message = format!("delete:attachments:1:{hash}:{now}");
+    let response = test::call_service(&app, delete(&message, now)).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = test::call_service(&app, delete(&message, now)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let request = test::TestRequest::get()
+        .uri(&format!("/attachments/1/{}?size=128", filenames[0]))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let request = test::TestRequest::get().uri("/attachments/1").to_request();
+    let page: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(page["items"].as_array().unwrap().len(), 2);
+
+    // Room was made for another item
+    let response = upload(&app, &attachments, "1", &animated_gif()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Singletons are replaced instead
+    let avatar = upload_filename(&app, &resource("avatars"), "1", ORANGE).await;
+    let hash = avatar.trim_end_matches(".png");
+    let request = test::TestRequest::delete()
+        .uri(&format!("/avatars/1/{hash}"))
+        .insert_header((
+            "X-Signature",
+            sign(format!("delete:avatars:1:{hash}:{now}").as_bytes()),
+        ))
+        .insert_header(("X-Signature-Timestamp", now.to_string()))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, request).await.status(),
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+}
+
+#[actix_web::test]
+async fn rollback_signatures_expire() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let avatars = resource("avatars");
+
+    let first = upload_filename(&app, &avatars, "1", ORANGE).await;
+    upload_filename(&app, &avatars, "1", &stripes(false)).await;
+    let hash = first.trim_end_matches(".png");
+    let now = now();
+
+    for (timestamp, signed, status) in [
+        // Replayed long after it was signed
+        (Some(now - 3600), now - 3600, StatusCode::UNAUTHORIZED),
+        // Signed for another time than it claims
+        (Some(now), now - 3600, StatusCode::UNAUTHORIZED),
+        (None, now, StatusCode::BAD_REQUEST),
+        (Some(now), now, StatusCode::OK),
+    ] {
+        let message = format!("rollback:avatars:1:{hash}:{signed}");
+        let mut request = test::TestRequest::post()
+            .uri(&format!("/avatars/1/rollback/{hash}"))
+            .insert_header(("X-Signature", sign(message.as_bytes())));
+
+        if let Some(timestamp) = timestamp {
+            request = request.insert_header(("X-Signature-Timestamp", timestamp.to_string()));
+        }
+
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(
+            response.status(),
+            status,
+            "{timestamp:?} signed for {signed}"
+        );
+    }
+
+    let request = test::TestRequest::get()
+        .uri("/avatars/1/versions")
+        .to_request();
+    let versions: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(versions["versions"].as_array().unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn long_text_fields_are_rejected() {
+    let storage_dir = TempDir::new().unwrap();
+    let app = app(&storage_dir, CdnConfig::default()).await;
+    let avatars = resource("avatars");
+    let long = "A".repeat(64 * 1024);
+
+    for body in [
+        multipart(ORANGE, &long, &[]),
+        multipart(ORANGE, &sign(ORANGE), &[("crop_signature", &long)]),
+        multipart(ORANGE, &sign(ORANGE), &[("focal_point", &long)]),
+    ] {
+        let response = upload_multipart(&app, &avatars, "1", body).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = test::read_body_json(response).await;
+        assert!(
+            body["error"].as_str().unwrap().contains("longer than"),
+            "{body}"
+        );
+    }
+}
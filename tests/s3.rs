@@ -0,0 +1,142 @@
+//! Runs against an S3-compatible store such as MinIO, and is skipped unless
+//! `S3_TEST_ENDPOINT` is set, e.g.:
+//!
+//! ```sh
+//! docker run -d -p 9000:9000 minio/minio server /data
+//! # create the bucket "rs-cdn-test", then
+//! S3_TEST_ENDPOINT=http://localhost:9000 cargo test --test s3
+//! ```
+//!
+//! `S3_TEST_BUCKET`, `S3_TEST_ACCESS_KEY` and `S3_TEST_SECRET_KEY` default to
+//! `rs-cdn-test` and the MinIO default credentials.
+mod common;
+
+use std::{
+    env,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use rs_cdn::{
+    config::{ResourceConfig, S3Config},
+    index::MetadataIndex,
+    rest::Resource,
+    storage::{s3::S3Backend, Storage, StorageBackend},
+};
+use tempfile::TempDir;
+
+use common::*;
+
+fn backend() -> Option<S3Backend> {
+    let Ok(endpoint) = env::var("S3_TEST_ENDPOINT") else {
+        eprintln!("S3_TEST_ENDPOINT is not set, skipping");
+        return None;
+    };
+
+    let var = |name: &str, default: &str| env::var(name).unwrap_or(default.to_string());
+
+    let config = S3Config {
+        bucket: var("S3_TEST_BUCKET", "rs-cdn-test"),
+        region: "us-east-1".to_string(),
+        endpoint: Some(endpoint),
+        access_key: Some(var("S3_TEST_ACCESS_KEY", "minioadmin")),
+        secret_key: Some(var("S3_TEST_SECRET_KEY", "minioadmin")),
+        path_style: true,
+    };
+
+    Some(S3Backend::new(&config).unwrap())
+}
+
+/// A prefix no other test run writes to.
+fn prefix() -> String {
+    format!("tests/{}", uuid::Uuid::new_v4())
+}
+
+#[test]
+fn objects_are_written_listed_and_deleted() {
+    let Some(backend) = backend() else {
+        return;
+    };
+    let prefix = prefix();
+    let key = format!("{prefix}/object");
+
+    assert_eq!(backend.get(&key).unwrap(), None);
+    assert!(!backend.exists(&key).unwrap());
+
+    backend.put(&key, b"data").unwrap();
+
+    assert_eq!(backend.get(&key).unwrap().as_deref(), Some(&b"data"[..]));
+    assert!(backend.exists(&key).unwrap());
+    assert_eq!(backend.list(&prefix).unwrap(), vec![key.clone()]);
+
+    backend.delete(&key).unwrap();
+
+    assert!(!backend.exists(&key).unwrap());
+    assert!(backend.list(&prefix).unwrap().is_empty());
+}
+
+#[test]
+fn locks_are_held_by_one_writer_at_a_time() {
+    let Some(backend) = backend() else {
+        return;
+    };
+    let key = prefix();
+    let released = AtomicBool::new(false);
+
+    let lock = backend.lock(&key).unwrap();
+
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| {
+            let _lock = backend.lock(&key).unwrap();
+            released.load(Ordering::SeqCst)
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+
+        assert!(waiter.join().unwrap());
+    });
+}
+
+#[test]
+fn uploads_are_stored_and_replaced() {
+    let Some(backend) = backend() else {
+        return;
+    };
+    let index_dir = TempDir::new().unwrap();
+    let index = MetadataIndex::open(index_dir.path().to_str().unwrap()).unwrap();
+    let storage = Storage::new(Arc::new(backend), index, 1);
+    let resource = Resource::new(ResourceConfig::new("avatars"));
+    let id = prefix().replace('/', "-");
+
+    let mut filenames = Vec::new();
+
+    for (image, hash) in [
+        (ORANGE.to_vec(), "a".repeat(40)),
+        (stripes(false), "b".repeat(40)),
+        (animated_gif(), "c".repeat(40)),
+    ] {
+        let filename = storage
+            .put(&resource, &id, Cursor::new(image), &hash, None)
+            .unwrap()
+            .filename;
+
+        assert!(storage.get(&resource, &id, &filename).unwrap().is_some());
+        filenames.push(filename);
+    }
+
+    // Only the current version and one previous one are kept
+    let versions = storage.versions(&resource, &id).unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].filename, filenames[2]);
+    assert!(storage
+        .get(&resource, &id, &filenames[0])
+        .unwrap()
+        .is_none());
+}
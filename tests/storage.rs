@@ -0,0 +1,463 @@
+mod common;
+
+use std::{
+    fs,
+    io::Cursor,
+    process,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use rs_cdn::{
+    config::ResourceConfig,
+    index::{MetadataIndex, ResourceMetadata},
+    rest::Resource,
+    storage::{
+        fs::FilesystemBackend, lock::BackendLock, ItemLimitReached, Storage, StorageBackend,
+    },
+};
+use tempfile::TempDir;
+
+use common::*;
+
+/// A filesystem backend whose writes of keys ending in `failing` fail.
+struct FlakyBackend {
+    inner: FilesystemBackend,
+    failing: Mutex<Option<&'static str>>,
+}
+
+impl StorageBackend for FlakyBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        match *self.failing.lock().unwrap() {
+            Some(suffix) if key.ends_with(suffix) => Err(anyhow!("Failed to write {key}")),
+            _ => self.inner.put(key, data),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key)
+    }
+}
+
+/// A filesystem backend that is slow to read, so that concurrent writes overlap.
+struct SlowBackend(FilesystemBackend);
+
+impl StorageBackend for SlowBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        thread::sleep(Duration::from_millis(50));
+        self.0.get(key)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.0.put(key, data)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.0.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.list(prefix)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.0.exists(key)
+    }
+
+    fn lock(&self, key: &str) -> Result<BackendLock> {
+        self.0.lock(key)
+    }
+}
+
+/// A filesystem backend whose locks are lost to another instance right away.
+struct LostLockBackend(FilesystemBackend);
+
+impl StorageBackend for LostLockBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.0.get(key)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.0.put(key, data)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.0.delete(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.0.list(prefix)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.0.exists(key)
+    }
+
+    fn lock(&self, key: &str) -> Result<BackendLock> {
+        Ok(self.0.lock(key)?.with_lost(Arc::new(AtomicBool::new(true))))
+    }
+}
+
+fn storage(dir: &TempDir, backend: Arc<dyn StorageBackend>) -> Storage {
+    let index = MetadataIndex::open(dir.path().join("index").to_str().unwrap()).unwrap();
+
+    Storage::new(backend, index, 5)
+}
+
+fn filesystem(dir: &TempDir) -> FilesystemBackend {
+    FilesystemBackend::new(dir.path().join("files").to_str().unwrap())
+}
+
+#[test]
+fn failed_writes_keep_the_files_of_the_current_version() {
+    let dir = TempDir::new().unwrap();
+    let backend = Arc::new(FlakyBackend {
+        inner: FilesystemBackend::new(dir.path().join("files").to_str().unwrap()),
+        failing: Mutex::new(None),
+    });
+    let storage = storage(&dir, backend.clone());
+    let avatars = Resource::new(ResourceConfig::new("avatars"));
+    let hash = "a".repeat(40);
+
+    let put = || storage.put(&avatars, "1", Cursor::new(animated_gif()), &hash, None);
+    let filename = put().unwrap().filename;
+
+    // The still is written again before the animation fails
+    *backend.failing.lock().unwrap() = Some(".webp");
+    assert!(put().is_err());
+
+    assert!(storage.get(&avatars, "1", &filename).unwrap().is_some());
+    assert_eq!(storage.versions(&avatars, "1").unwrap().len(), 1);
+}
+
+#[test]
+fn failed_writes_leave_nothing_indexed() {
+    let dir = TempDir::new().unwrap();
+    let backend = Arc::new(FlakyBackend {
+        inner: filesystem(&dir),
+        failing: Mutex::new(None),
+    });
+    let index = MetadataIndex::open(dir.path().join("index").to_str().unwrap()).unwrap();
+    let storage = Storage::new(backend.clone(), index.clone(), 5);
+    let attachments = attachments(5);
+    let hash = "a".repeat(40);
+
+    let put = || storage.put(&attachments, "1", Cursor::new(ORANGE), &hash, None);
+
+    // Listing the item fails after its files were written
+    *backend.failing.lock().unwrap() = Some(".json");
+    assert!(put().is_err());
+
+    assert!(index.get("attachments", "1", &hash).unwrap().is_none());
+    assert!(storage
+        .list(&attachments, "1", None, 10)
+        .unwrap()
+        .is_empty());
+    assert!(backend.list("attachments/1").unwrap().is_empty());
+
+    *backend.failing.lock().unwrap() = None;
+    put().unwrap();
+    assert!(index.get("attachments", "1", &hash).unwrap().is_some());
+}
+
+#[test]
+fn uploads_that_lost_their_lock_are_not_listed() {
+    let dir = TempDir::new().unwrap();
+    let backend = Arc::new(LostLockBackend(filesystem(&dir)));
+    let storage = storage(&dir, backend.clone());
+    let attachments = attachments(5);
+
+    let upload = storage.put(
+        &attachments,
+        "1",
+        Cursor::new(ORANGE),
+        &"a".repeat(40),
+        None,
+    );
+    assert!(upload.is_err());
+
+    assert!(storage
+        .list(&attachments, "1", None, 10)
+        .unwrap()
+        .is_empty());
+    assert!(backend.list("attachments/1").unwrap().is_empty());
+}
+
+#[test]
+fn rollbacks_remove_the_versions_they_replace() {
+    let dir = TempDir::new().unwrap();
+    let storage = storage(&dir, Arc::new(filesystem(&dir)));
+    let avatars = Resource::new(ResourceConfig::new("avatars"));
+    let hashes = ["a".repeat(40), "b".repeat(40), "c".repeat(40)];
+
+    let filenames: Vec<String> = hashes
+        .iter()
+        .map(|hash| {
+            storage
+                .put(&avatars, "1", Cursor::new(ORANGE), hash, None)
+                .unwrap()
+                .filename
+        })
+        .collect();
+
+    let filename = storage.rollback(&avatars, "1", &hashes[0]).unwrap();
+    assert_eq!(filename.as_ref(), Some(&filenames[0]));
+
+    let versions = storage.versions(&avatars, "1").unwrap();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].hash, hashes[0]);
+
+    assert!(storage.get(&avatars, "1", &filenames[0]).unwrap().is_some());
+
+    for (hash, filename) in hashes.iter().zip(&filenames).skip(1) {
+        assert!(storage.get(&avatars, "1", filename).unwrap().is_none());
+        assert!(storage.rollback(&avatars, "1", hash).unwrap().is_none());
+    }
+
+    assert_eq!(storage.metadata(&avatars, "1").unwrap().len(), 1);
+}
+
+#[test]
+fn concurrent_uploads_keep_every_version() {
+    let dir = TempDir::new().unwrap();
+    let instances = instances(&dir, Arc::new(SlowBackend(filesystem(&dir))));
+    let avatars = Resource::new(ResourceConfig::new("avatars"));
+
+    let hashes: Vec<String> = (0..6).map(|n| n.to_string().repeat(40)).collect();
+
+    thread::scope(|scope| {
+        for (n, hash) in hashes.iter().enumerate() {
+            let storage = &instances[n % instances.len()];
+            let avatars = &avatars;
+
+            scope.spawn(move || {
+                storage
+                    .put(avatars, "1", Cursor::new(ORANGE), hash, None)
+                    .unwrap()
+            });
+        }
+    });
+
+    let versions = instances[0].versions(&avatars, "1").unwrap();
+    assert_eq!(versions.len(), hashes.len());
+
+    for version in versions {
+        assert!(instances[0]
+            .get(&avatars, "1", &version.filename)
+            .unwrap()
+            .is_some());
+    }
+}
+
+#[test]
+fn concurrent_write_checks_succeed() {
+    let dir = TempDir::new().unwrap();
+    let storage = storage(&dir, Arc::new(filesystem(&dir)));
+
+    thread::scope(|scope| {
+        let checks: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| storage.check_writable()))
+            .collect();
+
+        for check in checks {
+            check.join().unwrap().unwrap();
+        }
+    });
+
+    // Missing files are already removed
+    filesystem(&dir).delete("avatars/1/missing.png").unwrap();
+}
+
+/// Instances sharing the files in `dir`, each with its own index.
+fn instances(dir: &TempDir, backend: Arc<dyn StorageBackend>) -> Vec<Storage> {
+    (0..2)
+        .map(|instance| {
+            let index_path = dir.path().join(format!("index-{instance}"));
+            let index = MetadataIndex::open(index_path.to_str().unwrap()).unwrap();
+
+            Storage::new(backend.clone(), index, 5)
+        })
+        .collect()
+}
+
+fn attachments(max_items: usize) -> Resource {
+    Resource::new(ResourceConfig {
+        singleton: false,
+        max_items: Some(max_items),
+        ..ResourceConfig::new("attachments")
+    })
+}
+
+#[test]
+fn concurrent_uploads_dont_exceed_the_item_limit() {
+    let dir = TempDir::new().unwrap();
+    let instances = instances(&dir, Arc::new(SlowBackend(filesystem(&dir))));
+    let attachments = attachments(2);
+
+    let stored = thread::scope(|scope| {
+        let uploads: Vec<_> = (0..6)
+            .map(|n| {
+                let storage = &instances[n % instances.len()];
+                let attachments = &attachments;
+                let hash = n.to_string().repeat(40);
+
+                scope.spawn(move || storage.put(attachments, "1", Cursor::new(ORANGE), &hash, None))
+            })
+            .collect();
+
+        uploads
+            .into_iter()
+            .filter_map(|upload| match upload.join().unwrap() {
+                Ok(filename) => Some(filename),
+                Err(why) => {
+                    assert!(why.is::<ItemLimitReached>(), "{why}");
+                    None
+                }
+            })
+            .count()
+    });
+
+    assert_eq!(stored, 2);
+
+    for storage in &instances {
+        assert_eq!(storage.list(&attachments, "1", None, 10).unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn items_are_shared_between_instances() {
+    let dir = TempDir::new().unwrap();
+    let instances = instances(&dir, Arc::new(filesystem(&dir)));
+    let attachments = attachments(2);
+    let hashes = ["a".repeat(40), "b".repeat(40)];
+
+    for (storage, hash) in instances.iter().zip(&hashes) {
+        storage
+            .put(&attachments, "1", Cursor::new(ORANGE), hash, None)
+            .unwrap();
+    }
+
+    // Both instances see the items of the other, and the limit they make up together
+    for storage in &instances {
+        let items = storage.list(&attachments, "1", None, 10).unwrap();
+        let listed: Vec<&String> = items.iter().map(|item| &item.hash).collect();
+
+        assert_eq!(listed, hashes.iter().collect::<Vec<_>>());
+        assert_eq!(
+            storage
+                .list(&attachments, "1", Some(&hashes[0]), 10)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let upload = storage.put(
+            &attachments,
+            "1",
+            Cursor::new(ORANGE),
+            &"c".repeat(40),
+            None,
+        );
+        assert!(upload.unwrap_err().is::<ItemLimitReached>());
+    }
+
+    // Items are removed by instances other than the one that stored them
+    assert!(instances[1].delete(&attachments, "1", &hashes[0]).unwrap());
+    assert!(!instances[0].delete(&attachments, "1", &hashes[0]).unwrap());
+
+    let items = instances[0].list(&attachments, "1", None, 10).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].hash, hashes[1]);
+    assert!(instances[0]
+        .get(&attachments, "1", &format!("{}.png", hashes[0]))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn recovery_keeps_the_staging_files_of_running_writes() {
+    let dir = TempDir::new().unwrap();
+    let backend = filesystem(&dir);
+    let files = dir.path().join("files/avatars/1");
+    fs::create_dir_all(&files).unwrap();
+
+    let staging = |name: &str| {
+        let path = files.join(name);
+        fs::write(&path, ORANGE).unwrap();
+        path
+    };
+
+    let running = staging(&format!("a.png.{}-0.staging", process::id()));
+    let dead = staging("b.png.4294967295-0.staging");
+    let stale = staging(&format!("c.png.{}-1.staging", process::id()));
+    fs::File::options()
+        .write(true)
+        .open(&stale)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+        .unwrap();
+
+    assert_eq!(backend.recover().unwrap(), 2);
+    assert!(running.exists());
+    assert!(!dead.exists());
+    assert!(!stale.exists());
+}
+
+#[test]
+fn indexed_items_are_migrated_to_storage() {
+    let dir = TempDir::new().unwrap();
+    let backend = filesystem(&dir);
+    let index = MetadataIndex::open(dir.path().join("index").to_str().unwrap()).unwrap();
+    let attachments = attachments(5);
+
+    let item = |hash: &str| ResourceMetadata {
+        resource: "attachments".to_string(),
+        id: "1".to_string(),
+        hash: hash.to_string(),
+        filename: format!("{hash}.png"),
+        format: "png".to_string(),
+        width: 1,
+        height: 1,
+        animated: false,
+        frame_count: 1,
+        size: ORANGE.len() as u64,
+        uploaded_at: 0,
+        files: vec![format!("{hash}.png")],
+        crop: None,
+    };
+
+    // Only the first one still has its file
+    let (stored, removed) = (item(&"a".repeat(40)), item(&"b".repeat(40)));
+    backend
+        .put(&format!("attachments/1/{}", stored.filename), ORANGE)
+        .unwrap();
+    index.insert(&stored).unwrap();
+    index.insert(&removed).unwrap();
+
+    let storage = Storage::new(Arc::new(backend), index, 5);
+    assert!(storage
+        .list(&attachments, "1", None, 10)
+        .unwrap()
+        .is_empty());
+
+    assert_eq!(storage.migrate_items().unwrap(), 1);
+    assert_eq!(storage.migrate_items().unwrap(), 0);
+
+    let items = storage.list(&attachments, "1", None, 10).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].hash, stored.hash);
+}